@@ -33,13 +33,15 @@ extern crate log;
 extern crate serde_derive;
 
 extern crate git2;
+extern crate hmac;
 extern crate libgit2_sys as git2_raw;
+extern crate rand;
 extern crate regex;
+extern crate rustc_serialize;
 extern crate serde;
+extern crate sha1;
 extern crate toml;
 
-#[cfg(test)]
-extern crate rand;
 #[cfg(test)]
 extern crate tempdir;
 #[cfg(test)]
@@ -52,17 +54,19 @@ mod utils;
 mod test;
 pub mod git;
 pub mod merger;
+pub mod ssh;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::ops::Deref;
 use std::str::FromStr;
 use std::string::ParseError;
+use std::time::Duration;
 use std::vec::Vec;
 
-use regex::RegexSet;
+use regex::{Regex, RegexSet};
 
-#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug, Default)]
 /// Configuration struct for the repository
 pub struct RepositoryConfiguration {
     /// URI to the repository remote.
@@ -76,22 +80,184 @@ pub struct RepositoryConfiguration {
     /// This is not really useful at the moment because fusionner will always merge to
     /// refs/fusionner/* and automatically adds the right refspecs for you
     pub push_refspecs: Vec<String>,
-    // Authentication Options
+    /// Credentials to authenticate with the remote
+    #[serde(flatten)]
+    pub credentials: Credentials,
+    /// The name to create merge commits under.
+    /// If unspecified, will use the global configuration in Git. Otherwise we will use some generic one
+    pub signature_name: Option<String>,
+    /// The email to create merge commits under.
+    /// If unspecified, will use the global configuration in Git. Otherwise we will use some generic one
+    pub signature_email: Option<String>,
+    /// Clone the repository without a working directory. Merges are created without touching the working
+    /// directory, so this saves disk space. Defaults to `false`
+    #[serde(default)]
+    pub bare: bool,
+    /// Path to a bare repository to use as a shared object cache. Clones only download the objects that are
+    /// missing from the cache, and refer to the objects in the cache instead of copying them. The cache is created
+    /// if it does not exist, and is refreshed before every clone. Existing checkouts are linked to the cache when
+    /// they are opened, and only download the objects missing from the cache from then on. Do not prune the objects
+    /// in the cache, i.e. with `git gc`, because checkouts depend on them
+    pub reference_repository: Option<String>,
+    /// Clone only the last `depth` commits of every branch, i.e. `git clone --depth`. libgit2 cannot make shallow
+    /// clones, so they are made with the `git` executable, which authenticates with its own configuration, i.e.
+    /// credential helpers and the SSH agent, instead of the credentials configured here. Later fetches download the
+    /// new commits down to the history already in the repository. Partial (i.e. blob-less) clones are not
+    /// supported, because libgit2 cannot download missing blobs when a merge needs them
+    pub depth: Option<u32>,
+    /// Number of commits to deepen a shallow repository by, i.e. `git fetch --deepen`, when a merge fails because
+    /// the merge base is missing. The merge is retried until it succeeds or the whole history has been fetched.
+    /// Defaults to `depth`, or `50` if `depth` is not set. Set to `0` to never deepen the repository
+    pub deepen_by: Option<u32>,
+    /// Move the repository at `checkout_path` aside and clone it again if it is corrupted, cannot be opened, or is
+    /// the remains of an interrupted clone. Otherwise, fusionner fails to start. Defaults to `false`
+    #[serde(default)]
+    pub reclone_on_corruption: bool,
+    /// Additional named remotes, with their own URI and credentials. The remotes are added to the repository,
+    /// or have their URI updated, when the repository is opened.
+    #[serde(default)]
+    pub remotes: HashMap<String, RemoteConfiguration>,
+    /// Name of the remote to push merge references and notes to, and fetch them from. Defaults to the remote
+    /// that topic branches and target references are fetched from
+    pub push_remote: Option<String>,
+    /// Path to an OpenSSH `known_hosts` file to verify the host keys of SSH remotes against.
+    /// Hashed host names are supported, but wildcard patterns and markers such as `@cert-authority` are not
+    pub known_hosts: Option<String>,
+    /// Host key fingerprints to accept for SSH remotes, keyed by host name. Fingerprints are in the form
+    /// `md5:<hex>` or `sha1:<hex>`. The fingerprints printed by `ssh-keygen -l -E md5` and
+    /// `ssh-keygen -l -E sha1` are also accepted
+    #[serde(default)]
+    pub host_key_fingerprints: HashMap<String, Vec<String>>,
+    /// Path to a file to record host keys of SSH remotes that are seen for the first time. Host keys that are
+    /// recorded are verified on subsequent connections. The file is created if it does not exist
+    pub trust_on_first_use: Option<String>,
+    /// URL of a proxy to connect to remotes through, i.e. `http://proxy.example.com:3128`. Use `auto` to
+    /// detect the proxy from the Git configuration and the environment
+    pub proxy: Option<String>,
+    /// Path to a bundle of PEM encoded CA certificates to verify the certificates of HTTPS remotes against,
+    /// instead of the system certificates. libgit2 loads the certificates once per process, so every repository
+    /// opened or cloned in a process must use the same `ca_bundle`. Otherwise, opening the repository fails
+    pub ca_bundle: Option<String>,
+    /// Whether the certificates of HTTPS remotes are verified. Defaults to `verify`. Client certificates are
+    /// not supported by libgit2
+    pub tls_verification: Option<TlsVerification>,
+    /// Maximum time, in seconds, to wait for a remote to respond when connecting to it. Timeouts are only detected
+    /// when libgit2 reports progress, so a connection that stalls completely is only interrupted by the operating
+    /// system
+    pub connect_timeout: Option<u64>,
+    /// Maximum time, in seconds, for each `ls-remote`, fetch, push or clone. Timeouts are only detected when libgit2
+    /// reports progress
+    pub operation_timeout: Option<u64>,
+    /// Retrying of remote operations that fail with transient errors, i.e. dropped connections
+    #[serde(default)]
+    pub retry: RetryConfiguration,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug, Default)]
+/// Configuration for a named remote, i.e. a mirror to push merge references to
+pub struct RemoteConfiguration {
+    /// URI to the remote
+    pub uri: String,
+    /// Credentials to authenticate with the remote
+    #[serde(flatten)]
+    pub credentials: Credentials,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug, Default)]
+/// Credentials to authenticate with a remote
+pub struct Credentials {
     /// Username to authenticate with the remote
     pub username: Option<String>,
     /// Password to authenticate with the remote
     pub password: Option<Password>,
-    /// Path to private key to authenticate with the remote. If the remote requrests for a key and
+    /// Path to a file containing the password, i.e. a secret mounted by your orchestrator. Used if `password`
+    /// is not specified. Trailing whitespace is ignored
+    pub password_file: Option<String>,
+    /// Name of an environment variable containing the password. Used if `password` and `password_file` are
+    /// not specified
+    pub password_env: Option<String>,
+    /// The password is an access token that does not require a username. If no username is specified, the token
+    /// is sent as the username with an empty password. Defaults to `false`
+    #[serde(default)]
+    pub token_only: bool,
+    /// Path to private key to authenticate with the remote. If the remote requests for a key and
     /// this is not specified, we will try to request the key from ssh-agent
     pub key: Option<String>,
+    /// Name of an environment variable containing the private key to authenticate with the remote. Used if `key`
+    /// is not specified
+    pub key_env: Option<String>,
     /// Passphrase to the private key for authentication
     pub key_passphrase: Option<Password>,
-    /// The name to create merge commits under.
-    /// If unspecified, will use the global configuration in Git. Otherwise we will use some generic one
-    pub signature_name: Option<String>,
-    /// The email to create merge commits under.
-    /// If unspecified, will use the global configuration in Git. Otherwise we will use some generic one
-    pub signature_email: Option<String>,
+    /// Path to a file containing the passphrase to the private key. Used if `key_passphrase` is not specified
+    pub key_passphrase_file: Option<String>,
+    /// Name of an environment variable containing the passphrase to the private key. Used if `key_passphrase`
+    /// and `key_passphrase_file` are not specified
+    pub key_passphrase_env: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[serde(default)]
+/// Configuration for retrying remote operations that fail with transient errors. Retries are delayed with
+/// exponential backoff.
+pub struct RetryConfiguration {
+    /// Number of times to retry an operation. Set to `0` to disable retries. Defaults to `3`
+    pub max_retries: u32,
+    /// Delay before the first retry, in milliseconds. The delay doubles with every retry. Defaults to `1000`
+    pub initial_backoff_ms: u64,
+    /// Maximum delay between retries, in milliseconds. Defaults to `30000`
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfiguration {
+    fn default() -> Self {
+        RetryConfiguration {
+            max_retries: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30000,
+        }
+    }
+}
+
+impl RetryConfiguration {
+    /// Returns the delay before the retry numbered `retry`, starting from `0`. The second half of the delay is
+    /// scaled by `jitter`, between `0` and `1`, so that many instances do not retry at the same time.
+    pub fn backoff(&self, retry: u32, jitter: f64) -> Duration {
+        let backoff = self
+            .initial_backoff_ms
+            .saturating_mul(2u64.saturating_pow(retry))
+            .min(self.max_backoff_ms);
+        let jitter = (backoff as f64 / 2.0 * jitter.clamp(0.0, 1.0)) as u64;
+        Duration::from_millis(backoff - backoff / 2 + jitter)
+    }
+}
+
+impl RepositoryConfiguration {
+    /// Check that the `fetch_refspecs` and `push_refspecs` are valid refspecs that can be added to the
+    /// remote configuration
+    pub fn validate_refspecs(&self) -> Result<(), git::RefspecError> {
+        for refspec in self.fetch_refspecs.iter().chain(&self.push_refspecs) {
+            if git::RefspecStr::parse(refspec)?.negative() {
+                return Err(git::RefspecError::UnsupportedNegative(refspec.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the configuration to use with the named remote. If the remote is configured in `remotes`,
+    /// the URI and credentials are replaced with those of the remote.
+    pub fn for_remote(&self, remote: &str) -> RepositoryConfiguration {
+        let mut configuration = self.clone();
+        if let Some(remote) = self.remotes.get(remote) {
+            configuration.uri = remote.uri.clone();
+            configuration.credentials = remote.credentials.clone();
+        }
+        configuration
+    }
+
+    /// Returns the number of commits to deepen a shallow repository by, from `deepen_by` or `depth`
+    pub fn deepen_by_or_default(&self) -> u32 {
+        self.deepen_by.or(self.depth).unwrap_or(50)
+    }
 }
 
 #[derive(Deserialize, Serialize, PartialOrd, Eq, PartialEq, Clone)]
@@ -138,11 +304,111 @@ impl FromStr for Password {
     }
 }
 
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug, Default)]
+/// Configuration for the references to watch for changes, and the target references they should be merged into
+pub struct WatchConfiguration {
+    /// Exact names of references to watch
+    #[serde(default)]
+    pub exacts: Vec<String>,
+    /// Regular expressions matching the references to watch
+    #[serde(default)]
+    pub regexes: Vec<String>,
+    /// Glob patterns matching the references to watch. See `glob_to_regex` for the supported syntax.
+    #[serde(default)]
+    pub globs: Vec<String>,
+    /// Exact names of references to exclude, even if they were matched by `exacts` or `regexes`
+    #[serde(default)]
+    pub exclude_exacts: Vec<String>,
+    /// Regular expressions matching references to exclude, even if they were matched by `exacts` or `regexes`
+    #[serde(default)]
+    pub exclude_regexes: Vec<String>,
+    /// Glob patterns matching references to exclude, even if they were matched by `exacts` or `regexes`
+    #[serde(default)]
+    pub exclude_globs: Vec<String>,
+    /// Template to derive the target reference from the captures of the first regex in `regexes` that matches
+    /// a watched reference. For example, the regex `^refs/heads/for/(?P<target>[^/]+)/.*$` with the template
+    /// `refs/heads/$target` will merge `refs/heads/for/develop/topic` into `refs/heads/develop`.
+    pub derive_target: Option<String>,
+    /// The target references that the watched references will be merged into. If empty, the default target
+    /// references will be used. See `WatchGroup::from_configuration`.
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// Skip watched references whose head commit is older than this number of seconds, according to the
+    /// committer time
+    pub max_age: Option<u64>,
+    /// Skip watched references whose head commit message contains any of these markers, i.e. `[skip fusionner]`
+    #[serde(default)]
+    pub skip_markers: Vec<String>,
+    /// Skip watched references whose head commit message has any of these trailers, i.e. `Fusionner: skip`.
+    /// Trailer keys are compared case insensitively.
+    #[serde(default)]
+    pub skip_trailers: Vec<String>,
+    /// Order in which the watched references are processed. Defaults to `WatchOrder::Pattern`.
+    /// See `WatchReferences::prioritise`.
+    pub order: Option<WatchOrder>,
+    /// Regular expressions matching watched references that are always processed first
+    #[serde(default)]
+    pub priority_regexes: Vec<String>,
+    /// Glob patterns matching watched references that are always processed first
+    #[serde(default)]
+    pub priority_globs: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+/// Order in which watched references are processed
+pub enum WatchOrder {
+    /// In the order of the patterns that matched them: exacts, followed by regexes and globs
+    Pattern,
+    /// Most recently updated references first, according to the committer time of their head commit
+    Recent,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+/// Policy for verifying the certificates of HTTPS remotes
+pub enum TlsVerification {
+    /// Certificates must be valid and issued by a trusted CA
+    Verify,
+    /// Certificates are not verified. This is insecure, and should only be used for testing
+    Insecure,
+}
+
+impl WatchConfiguration {
+    /// Returns `true` if the configuration would include any reference at all
+    pub fn has_inclusions(&self) -> bool {
+        !(self.exacts.is_empty() && self.regexes.is_empty() && self.globs.is_empty())
+    }
+}
+
 #[derive(Debug)]
 /// Convenience struct to hold references to watch for changes to be merged into some `target_reference`.
 pub struct WatchReferences {
     regex_set: RegexSet,
     exact_list: Vec<String>,
+    exclude_regex_set: RegexSet,
+    exclude_exact_list: Vec<String>,
+    regexes: Vec<Regex>,
+    priority_regex_set: RegexSet,
+    order: WatchOrder,
+    derive_target: Option<String>,
+    max_age: Option<u64>,
+    skip_markers: Vec<String>,
+    skip_trailers: Vec<String>,
+}
+
+/// The reason a watched reference is skipped. Returned by `WatchReferences::skip_reason`.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum SkipReason {
+    /// The head commit is older than the configured maximum age
+    TooOld {
+        /// Age of the commit, in seconds
+        age: u64,
+    },
+    /// The commit message contains an opt-out marker
+    OptOutMarker(String),
+    /// The commit message contains an opt-out trailer
+    OptOutTrailer(String),
 }
 
 impl WatchReferences {
@@ -152,17 +418,55 @@ impl WatchReferences {
     where
         T: std::fmt::Display,
     {
-        let exact_list = exacts.iter().map(|s| s.to_string()).collect();
-        let regex_set = RegexSet::new(regexes)?;
+        let configuration = WatchConfiguration {
+            exacts: exacts.iter().map(|s| s.to_string()).collect(),
+            regexes: regexes.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+
+        Self::from_configuration(&configuration)
+    }
+
+    /// Create watch references from a `WatchConfiguration`. References matched by the exclusions
+    /// will never be watched, regardless of whether they were included.
+    ///
+    /// Globs are converted to regular expressions with `glob_to_regex`.
+    pub fn from_configuration(configuration: &WatchConfiguration) -> Result<WatchReferences, regex::Error> {
+        let regexes = Self::regexes_with_globs(&configuration.regexes, &configuration.globs);
+        let exclude_regexes = Self::regexes_with_globs(&configuration.exclude_regexes, &configuration.exclude_globs);
+        let priority_regexes = Self::regexes_with_globs(&configuration.priority_regexes, &configuration.priority_globs);
+
+        let individual_regexes = configuration
+            .regexes
+            .iter()
+            .map(|regex| Regex::new(regex))
+            .collect::<Result<Vec<Regex>, regex::Error>>()?;
 
         Ok(WatchReferences {
-            regex_set: regex_set,
-            exact_list: exact_list,
+            regex_set: RegexSet::new(&regexes)?,
+            exact_list: configuration.exacts.clone(),
+            exclude_regex_set: RegexSet::new(&exclude_regexes)?,
+            exclude_exact_list: configuration.exclude_exacts.clone(),
+            regexes: individual_regexes,
+            priority_regex_set: RegexSet::new(&priority_regexes)?,
+            order: configuration.order.unwrap_or(WatchOrder::Pattern),
+            derive_target: configuration.derive_target.clone(),
+            max_age: configuration.max_age,
+            skip_markers: configuration.skip_markers.clone(),
+            skip_trailers: configuration.skip_trailers.clone(),
         })
     }
 
+    fn regexes_with_globs(regexes: &[String], globs: &[String]) -> Vec<String> {
+        regexes
+            .iter()
+            .cloned()
+            .chain(globs.iter().map(|glob| glob_to_regex(glob)))
+            .collect()
+    }
+
     /// Given a set of Remote heads as advertised by the remote, return a set of remtoe heads
-    /// which exist based on the watch references
+    /// which exist based on the watch references. Exclusions are applied after the inclusions.
     pub fn resolve_watch_refs(&self, remote_ls: &[git::RemoteHead]) -> HashSet<String> {
         let mut refs = HashSet::new();
 
@@ -177,6 +481,613 @@ impl WatchReferences {
             refs.insert(regex_match.to_string());
         }
 
+        refs.retain(|reference| !self.is_excluded(reference));
         refs
     }
+
+    /// Check if a reference is excluded from being watched
+    pub fn is_excluded(&self, reference: &str) -> bool {
+        self.exclude_exact_list.iter().any(|exclude| exclude == reference) || self.exclude_regex_set.is_match(reference)
+    }
+
+    /// Sort watched references and their head commits in the order they should be processed.
+    ///
+    /// References matching the priority patterns always come first. The rest are ordered according to the
+    /// configured `WatchOrder`, and ties are broken by the reference name. Make sure you have fetched the
+    /// commits first, otherwise they are considered to be the oldest.
+    pub fn prioritise<T: AsRef<str>>(&self, repository: &git2::Repository, references: &mut Vec<(T, git2::Oid)>) {
+        let mut keys: Vec<_> = references
+            .iter()
+            .enumerate()
+            .map(|(index, &(ref reference, oid))| {
+                let reference = reference.as_ref();
+                let recency = match self.order {
+                    WatchOrder::Pattern => 0,
+                    WatchOrder::Recent => repository
+                        .find_commit(oid)
+                        .map(|commit| -commit.time().seconds())
+                        .unwrap_or(0),
+                };
+                (
+                    !self.priority_regex_set.is_match(reference),
+                    recency,
+                    self.pattern_index(reference),
+                    reference.to_string(),
+                    index,
+                )
+            })
+            .collect();
+        keys.sort();
+
+        let mut unsorted: Vec<Option<(T, git2::Oid)>> = references.drain(..).map(Some).collect();
+        references.extend(keys.into_iter().filter_map(|key| unsorted[key.4].take()));
+    }
+
+    /// Index of the first inclusion pattern matching the reference, counting exacts first, followed by
+    /// regexes and globs.
+    fn pattern_index(&self, reference: &str) -> usize {
+        match self.exact_list.iter().position(|exact| exact == reference) {
+            Some(index) => index,
+            None => {
+                let matches = self.regex_set.matches(reference);
+                self.exact_list.len() + matches.iter().next().unwrap_or_else(|| self.regex_set.len())
+            }
+        }
+    }
+
+    /// Determine if the head commit of a watched reference should be skipped, based on its age and the
+    /// opt-out markers and trailers in its message. Make sure you have fetched the commit first.
+    pub fn skip_reason(&self, commit: &git2::Commit) -> Option<SkipReason> {
+        if let Some(max_age) = self.max_age {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|duration| duration.as_secs() as i64)
+                .unwrap_or(0);
+            let age = now - commit.time().seconds();
+            if age > max_age as i64 {
+                return Some(SkipReason::TooOld { age: age as u64 });
+            }
+        }
+
+        let message = commit.message().unwrap_or("");
+        if let Some(marker) = self
+            .skip_markers
+            .iter()
+            .find(|marker| message.contains(marker.as_str()))
+        {
+            return Some(SkipReason::OptOutMarker(marker.to_string()));
+        }
+
+        let trailers = parse_trailers(message);
+        self.skip_trailers
+            .iter()
+            .find(|skip_trailer| match parse_trailer(skip_trailer) {
+                Some(skip_trailer) => trailers.contains(&skip_trailer),
+                None => false,
+            })
+            .map(|trailer| SkipReason::OptOutTrailer(trailer.to_string()))
+    }
+
+    /// Derive the target reference for a watched reference from the captures of the first regex that matches it,
+    /// if a `derive_target` template was configured. Returns `Ok(None)` if no target could be derived, in which case
+    /// the configured target references should be used instead.
+    ///
+    /// Returns an error if the derived target reference does not exist on the remote.
+    pub fn derive_target_ref(
+        &self,
+        reference: &str,
+        remote_ls: &[git::RemoteHead],
+    ) -> Result<Option<String>, git2::Error> {
+        let template = match self.derive_target {
+            Some(ref template) => template,
+            None => return Ok(None),
+        };
+
+        let captures = match self.regexes.iter().filter_map(|regex| regex.captures(reference)).next() {
+            Some(captures) => captures,
+            None => return Ok(None),
+        };
+
+        let mut target_ref = String::new();
+        captures.expand(template, &mut target_ref);
+        if target_ref.is_empty() {
+            return Ok(None);
+        }
+
+        if remote_ls.iter().all(|head| head.name != target_ref) {
+            return Err(git_err!(&format!(
+                "Target reference {} derived from {} does not exist on the remote",
+                target_ref, reference
+            )));
+        }
+        Ok(Some(target_ref))
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SkipReason::TooOld { age } => write!(f, "Commit is too old ({} seconds)", age),
+            SkipReason::OptOutMarker(ref marker) => write!(f, "Commit message contains `{}`", marker),
+            SkipReason::OptOutTrailer(ref trailer) => write!(f, "Commit message has the trailer `{}`", trailer),
+        }
+    }
+}
+
+/// Parse the trailers of a commit message. Like Git, only the last paragraph is a trailer block, and only if it
+/// follows the subject and every line in it is a trailer, or the continuation of one
+fn parse_trailers(message: &str) -> Vec<(String, &str)> {
+    let paragraphs: Vec<&str> = message
+        .trim()
+        .split("\n\n")
+        .filter(|paragraph| !paragraph.trim().is_empty())
+        .collect();
+    if paragraphs.len() < 2 {
+        return vec![];
+    }
+
+    let mut trailers = vec![];
+    for line in paragraphs[paragraphs.len() - 1].trim_matches('\n').lines() {
+        if line.starts_with(char::is_whitespace) && !trailers.is_empty() {
+            continue;
+        }
+        match parse_trailer(line) {
+            Some(trailer) => trailers.push(trailer),
+            None => return vec![],
+        }
+    }
+    trailers
+}
+
+/// Parse a `Key: value` trailer line into a lowercased key and a trimmed value
+fn parse_trailer(line: &str) -> Option<(String, &str)> {
+    let separator = line.find(':')?;
+    let key = line[0..separator].trim();
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((key.to_lowercase(), line[(separator + 1)..].trim()))
+}
+
+#[derive(Debug)]
+/// A group of watch references that are merged into their own target references.
+///
+/// Multiple groups can share the same `Remote` and `Merger`, so that different sets of topic branches
+/// are merged into different target branches.
+pub struct WatchGroup {
+    /// References to watch for this group
+    pub watch_refs: WatchReferences,
+    /// The references that each of the watched references will be merged into. `HEAD` refers to the remote's
+    /// default branch.
+    pub target_references: Vec<String>,
+}
+
+impl WatchGroup {
+    /// Create a new group of watch references to be merged into each of the `target_references`
+    pub fn new<T: AsRef<str>>(watch_refs: WatchReferences, target_references: &[T]) -> WatchGroup {
+        WatchGroup {
+            watch_refs,
+            target_references: target_references.iter().map(|s| s.as_ref().to_string()).collect(),
+        }
+    }
+
+    /// Create a new group from a `WatchConfiguration`. If the configuration does not specify any target
+    /// references, the watched references will be merged into `default_target_references` instead.
+    pub fn from_configuration<T: AsRef<str>>(
+        configuration: &WatchConfiguration,
+        default_target_references: &[T],
+    ) -> Result<WatchGroup, regex::Error> {
+        let watch_refs = WatchReferences::from_configuration(configuration)?;
+        if configuration.targets.is_empty() {
+            Ok(WatchGroup::new(watch_refs, default_target_references))
+        } else {
+            Ok(WatchGroup::new(watch_refs, &configuration.targets))
+        }
+    }
+
+    /// Resolve the watch references for each group, in order. The returned `Vec` has one entry per group.
+    ///
+    /// A reference is only resolved for the first group that it matches, so more specific groups should
+    /// come before more general ones.
+    pub fn resolve_watch_groups(groups: &[WatchGroup], remote_ls: &[git::RemoteHead]) -> Vec<HashSet<String>> {
+        let mut claimed = HashSet::new();
+
+        groups
+            .iter()
+            .map(|group| {
+                let refs: HashSet<String> = group
+                    .watch_refs
+                    .resolve_watch_refs(remote_ls)
+                    .into_iter()
+                    .filter(|reference| !claimed.contains(reference))
+                    .collect();
+                claimed.extend(refs.iter().cloned());
+                refs
+            })
+            .collect()
+    }
+}
+
+/// Convert a Git style glob pattern for references into an anchored regular expression.
+///
+/// - `*` matches any number of characters, including `/`, like a Git refspec wildcard. `refs/heads/*` will match
+///   `refs/heads/feature` as well as `refs/heads/feature/x`
+/// - `**` matches any number of characters too. A `**/` path component matches zero or more path components, so
+///   `refs/heads/**/fix` will match `refs/heads/fix` as well as `refs/heads/a/b/fix`
+/// - `?` matches a single character within a path component
+/// - `[...]` matches a character class, and `[!...]` matches a negated character class
+///
+/// All other characters are matched literally.
+///
+/// # Examples
+/// ```
+/// use fusionner::glob_to_regex;
+///
+/// assert_eq!("^refs/heads/.*$", glob_to_regex("refs/heads/*"));
+/// assert_eq!("^refs/heads/(?:.*/)?fix$", glob_to_regex("refs/heads/**/fix"));
+/// ```
+pub fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = "^".to_string();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                let component_start = i == 0 || chars[i - 1] == '/';
+                if component_start && chars.get(i + 2) == Some(&'/') {
+                    // `**/` matches zero or more whole path components
+                    regex.push_str("(?:.*/)?");
+                    i += 3;
+                } else {
+                    regex.push_str(".*");
+                    i += 2;
+                }
+            }
+            '*' => {
+                regex.push_str(".*");
+                i += 1;
+            }
+            '?' => {
+                regex.push_str("[^/]");
+                i += 1;
+            }
+            '[' => match chars[i + 1..].iter().position(|c| *c == ']') {
+                Some(length) if length > 0 => {
+                    let mut class: String = chars[i + 1..i + 1 + length].iter().collect();
+                    if class.starts_with('!') {
+                        class.replace_range(..1, "^");
+                    }
+                    regex.push_str(&format!("[{}]", class.replace("\\", "\\\\")));
+                    i += length + 2;
+                }
+                _ => {
+                    regex.push_str("\\[");
+                    i += 1;
+                }
+            },
+            c => {
+                regex.push_str(&regex::escape(&c.to_string()));
+                i += 1;
+            }
+        }
+    }
+
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        glob_to_regex, RetryConfiguration, SkipReason, WatchConfiguration, WatchGroup, WatchOrder, WatchReferences,
+    };
+    use git::RemoteHead;
+    use git2;
+    use regex::Regex;
+
+    fn commit(repo: &git2::Repository, message: &str, time: i64) -> git2::Oid {
+        let signature = not_err!(git2::Signature::new("name", "email", &git2::Time::new(time, 0)));
+        let tree_oid = not_err!(not_err!(repo.index()).write_tree());
+        let tree = not_err!(repo.find_tree(tree_oid));
+        not_err!(repo.commit(None, &signature, &signature, message, &tree, &[]))
+    }
+
+    fn now() -> i64 {
+        let now = not_err!(::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH));
+        now.as_secs() as i64
+    }
+
+    fn remote_heads(names: &[&str]) -> Vec<RemoteHead> {
+        names
+            .iter()
+            .map(|name| RemoteHead {
+                is_local: false,
+                oid: git2::Oid::zero(),
+                loid: git2::Oid::zero(),
+                name: name.to_string(),
+                symref_target: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn watch_references_are_resolved() {
+        let remote_ls = remote_heads(&["refs/heads/master", "refs/heads/feature", "refs/pull/1/head"]);
+        let watch_refs = not_err!(WatchReferences::new(&["refs/heads/feature"], &["^refs/pull/.*$"]));
+
+        let resolved = watch_refs.resolve_watch_refs(&remote_ls);
+        assert_eq!(2, resolved.len());
+        assert!(resolved.contains("refs/heads/feature"));
+        assert!(resolved.contains("refs/pull/1/head"));
+    }
+
+    #[test]
+    fn exclusions_are_applied_after_inclusions() {
+        let remote_ls = remote_heads(&[
+            "refs/heads/master",
+            "refs/heads/feature",
+            "refs/heads/release-1.0",
+            "refs/heads/wip/something",
+        ]);
+        let configuration = WatchConfiguration {
+            regexes: vec!["^refs/heads/.*$".to_string()],
+            exclude_exacts: vec!["refs/heads/master".to_string()],
+            exclude_regexes: vec!["^refs/heads/release-.*$".to_string(), "^refs/heads/wip/.*$".to_string()],
+            ..Default::default()
+        };
+        let watch_refs = not_err!(WatchReferences::from_configuration(&configuration));
+
+        let resolved = watch_refs.resolve_watch_refs(&remote_ls);
+        assert_eq!(1, resolved.len());
+        assert!(resolved.contains("refs/heads/feature"));
+    }
+
+    #[test]
+    fn globs_are_converted_to_regex_correctly() {
+        let test_values = vec![
+            ("refs/heads/*", "refs/heads/feature", true),
+            ("refs/heads/*", "refs/heads/wip/feature", true),
+            ("refs/heads/feature-*", "refs/heads/feature-a/b", true),
+            ("refs/heads/feature-*", "refs/heads/fix-a", false),
+            ("refs/heads/**", "refs/heads/wip/feature", true),
+            ("refs/heads/**/fix", "refs/heads/fix", true),
+            ("refs/heads/**/fix", "refs/heads/a/b/fix", true),
+            ("refs/heads/**/fix", "refs/heads/a/prefix", false),
+            ("refs/pull/*/head", "refs/pull/12/head", true),
+            ("refs/pull/*/head", "refs/pull/12/merge", false),
+            ("refs/heads/v?.x", "refs/heads/v2.x", true),
+            ("refs/heads/v?.x", "refs/heads/v2-x", false),
+            ("refs/heads/v[0-9].x", "refs/heads/v2.x", true),
+            ("refs/heads/v[!0-9].x", "refs/heads/v2.x", false),
+            ("refs/heads/a+b", "refs/heads/a+b", true),
+            ("refs/heads/a+b", "refs/heads/aab", false),
+            ("refs/heads/[", "refs/heads/[", true),
+        ];
+
+        for (glob, reference, expected) in test_values {
+            let regex = not_err!(Regex::new(&glob_to_regex(glob)));
+            assert_eq!(expected, regex.is_match(reference), "{} matching {}", glob, reference);
+        }
+    }
+
+    #[test]
+    fn globs_can_be_used_for_inclusions_and_exclusions() {
+        let remote_ls = remote_heads(&[
+            "refs/heads/master",
+            "refs/heads/feature",
+            "refs/heads/wip/something",
+            "refs/tags/v1.0",
+        ]);
+        let configuration = WatchConfiguration {
+            globs: vec!["refs/heads/**".to_string()],
+            exclude_globs: vec!["refs/heads/wip/*".to_string()],
+            exclude_exacts: vec!["refs/heads/master".to_string()],
+            ..Default::default()
+        };
+        let watch_refs = not_err!(WatchReferences::from_configuration(&configuration));
+
+        let resolved = watch_refs.resolve_watch_refs(&remote_ls);
+        assert_eq!(1, resolved.len());
+        assert!(resolved.contains("refs/heads/feature"));
+    }
+
+    #[test]
+    fn references_are_resolved_into_the_first_matching_group() {
+        let remote_ls = remote_heads(&[
+            "refs/heads/master",
+            "refs/heads/feature",
+            "refs/heads/release-2.x",
+            "refs/heads/release-2.x/fix",
+        ]);
+        let release = WatchConfiguration {
+            globs: vec!["refs/heads/release-2.x/**".to_string()],
+            ..Default::default()
+        };
+        let everything = WatchConfiguration {
+            globs: vec!["refs/heads/**".to_string()],
+            exclude_exacts: vec!["refs/heads/master".to_string(), "refs/heads/release-2.x".to_string()],
+            ..Default::default()
+        };
+        let groups = vec![
+            WatchGroup::new(
+                not_err!(WatchReferences::from_configuration(&release)),
+                &["refs/heads/release-2.x"],
+            ),
+            WatchGroup::new(
+                not_err!(WatchReferences::from_configuration(&everything)),
+                &["HEAD", "refs/heads/release-1.x"],
+            ),
+        ];
+
+        let resolved = WatchGroup::resolve_watch_groups(&groups, &remote_ls);
+        assert_eq!(2, resolved.len());
+        assert_eq!(1, resolved[0].len());
+        assert!(resolved[0].contains("refs/heads/release-2.x/fix"));
+        assert_eq!(1, resolved[1].len());
+        assert!(resolved[1].contains("refs/heads/feature"));
+    }
+
+    #[test]
+    fn target_references_are_derived_from_captures() {
+        let remote_ls = remote_heads(&[
+            "refs/heads/master",
+            "refs/heads/develop",
+            "refs/heads/for/develop/topic",
+            "refs/heads/for/unknown/topic",
+            "refs/heads/other",
+        ]);
+        let configuration = WatchConfiguration {
+            regexes: vec!["^refs/heads/for/(?P<target>[^/]+)/.*$".to_string()],
+            exacts: vec!["refs/heads/other".to_string()],
+            derive_target: Some("refs/heads/$target".to_string()),
+            ..Default::default()
+        };
+        let watch_refs = not_err!(WatchReferences::from_configuration(&configuration));
+
+        let derived = not_err!(watch_refs.derive_target_ref("refs/heads/for/develop/topic", &remote_ls));
+        assert_eq!(Some("refs/heads/develop".to_string()), derived);
+
+        let derived = not_err!(watch_refs.derive_target_ref("refs/heads/other", &remote_ls));
+        is_none!(derived);
+
+        is_err!(watch_refs.derive_target_ref("refs/heads/for/unknown/topic", &remote_ls));
+    }
+
+    #[test]
+    fn watch_groups_use_default_targets_when_unspecified() {
+        let configuration = WatchConfiguration {
+            globs: vec!["refs/heads/*".to_string()],
+            ..Default::default()
+        };
+        let group = not_err!(WatchGroup::from_configuration(&configuration, &["HEAD"]));
+        assert_eq!(vec!["HEAD".to_string()], group.target_references);
+
+        let configuration = WatchConfiguration {
+            globs: vec!["refs/heads/*".to_string()],
+            targets: vec!["refs/heads/develop".to_string(), "refs/heads/release".to_string()],
+            ..Default::default()
+        };
+        let group = not_err!(WatchGroup::from_configuration(&configuration, &["HEAD"]));
+        assert_eq!(configuration.targets, group.target_references);
+    }
+
+    #[test]
+    fn old_commits_are_skipped() {
+        let (_td, repo) = ::test::raw_repo_init();
+        let configuration = WatchConfiguration {
+            max_age: Some(3600),
+            ..Default::default()
+        };
+        let watch_refs = not_err!(WatchReferences::from_configuration(&configuration));
+
+        let recent = not_err!(repo.find_commit(commit(&repo, "Recent", now() - 60)));
+        is_none!(watch_refs.skip_reason(&recent));
+
+        let old = not_err!(repo.find_commit(commit(&repo, "Old", now() - 7200)));
+        assert_matches!(watch_refs.skip_reason(&old), Some(SkipReason::TooOld { .. }));
+    }
+
+    #[test]
+    fn commits_with_opt_out_markers_and_trailers_are_skipped() {
+        let (_td, repo) = ::test::raw_repo_init();
+        let configuration = WatchConfiguration {
+            skip_markers: vec!["[skip fusionner]".to_string()],
+            skip_trailers: vec!["Fusionner: skip".to_string()],
+            ..Default::default()
+        };
+        let watch_refs = not_err!(WatchReferences::from_configuration(&configuration));
+
+        let test_values = vec![
+            ("Add feature", None),
+            (
+                "Add feature [skip fusionner]",
+                Some(SkipReason::OptOutMarker("[skip fusionner]".to_string())),
+            ),
+            (
+                "Add feature\n\nSome description\n\nSigned-off-by: name\nfusionner: skip",
+                Some(SkipReason::OptOutTrailer("Fusionner: skip".to_string())),
+            ),
+            (
+                "Add feature\n\nFusionner: skip is mentioned in the body\n\nSigned-off-by: name",
+                None,
+            ),
+            ("Fusionner: skip", None),
+            ("Add feature\n\nSome description\nFusionner: skip", None),
+            (
+                "Add feature\n\nFusionner: skip\nSigned-off-by: name\n  <name@example.com>",
+                Some(SkipReason::OptOutTrailer("Fusionner: skip".to_string())),
+            ),
+        ];
+
+        for (message, expected) in test_values {
+            let commit = not_err!(repo.find_commit(commit(&repo, message, now())));
+            assert_eq!(expected, watch_refs.skip_reason(&commit), "{}", message);
+        }
+    }
+
+    #[test]
+    fn references_are_prioritised() {
+        let (_td, repo) = ::test::raw_repo_init();
+        let old = commit(&repo, "Old", now() - 7200);
+        let recent = commit(&repo, "Recent", now() - 60);
+        let configuration = WatchConfiguration {
+            exacts: vec!["refs/heads/develop".to_string()],
+            globs: vec!["refs/heads/feature/*".to_string(), "refs/heads/**".to_string()],
+            priority_globs: vec!["refs/heads/hotfix/*".to_string()],
+            ..Default::default()
+        };
+        let mut references = vec![
+            ("refs/heads/other", recent),
+            ("refs/heads/feature/b", old),
+            ("refs/heads/feature/a", recent),
+            ("refs/heads/hotfix/a", old),
+            ("refs/heads/develop", old),
+        ];
+
+        let watch_refs = not_err!(WatchReferences::from_configuration(&configuration));
+        watch_refs.prioritise(&repo, &mut references);
+        let expected = vec![
+            "refs/heads/hotfix/a",
+            "refs/heads/develop",
+            "refs/heads/feature/a",
+            "refs/heads/feature/b",
+            "refs/heads/other",
+        ];
+        assert_eq!(expected, references.iter().map(|&(r, _)| r).collect::<Vec<_>>());
+
+        let configuration = WatchConfiguration {
+            order: Some(WatchOrder::Recent),
+            ..configuration
+        };
+        let watch_refs = not_err!(WatchReferences::from_configuration(&configuration));
+        watch_refs.prioritise(&repo, &mut references);
+        let expected = vec![
+            "refs/heads/hotfix/a",
+            "refs/heads/feature/a",
+            "refs/heads/other",
+            "refs/heads/develop",
+            "refs/heads/feature/b",
+        ];
+        assert_eq!(expected, references.iter().map(|&(r, _)| r).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        let retry = RetryConfiguration {
+            max_retries: 10,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 5000,
+        };
+
+        assert_eq!(Duration::from_millis(500), retry.backoff(0, 0.0));
+        assert_eq!(Duration::from_millis(1000), retry.backoff(0, 1.0));
+        assert_eq!(Duration::from_millis(1500), retry.backoff(1, 0.5));
+        assert_eq!(Duration::from_millis(4000), retry.backoff(2, 1.0));
+        assert_eq!(Duration::from_millis(5000), retry.backoff(3, 1.0));
+        assert_eq!(Duration::from_millis(2500), retry.backoff(63, 0.0));
+        assert_eq!(Duration::from_millis(5000), retry.backoff(u32::MAX, 2.0));
+    }
 }
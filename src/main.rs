@@ -28,6 +28,8 @@ mod test;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Read;
+use std::rc::Rc;
+use std::time::Duration;
 use std::vec::Vec;
 
 use docopt::Docopt;
@@ -38,18 +40,35 @@ const USAGE: &'static str = "
 fusionner
 
 Usage:
-  fusionner [options] <configuration-file> (<watch-ref> | --watch-regex=<regex>)...
+  fusionner [options] <configuration-file> [<watch-ref> | --watch-regex=<regex> | --watch-glob=<glob> | --exclude-ref=<reference> | --exclude-regex=<regex> | --exclude-glob=<glob> | --watch-group=<group> | --target-reference=<reference>]...
   fusionner -h | --help
 
 Use with a <configuration-file> to specify your repository information.
 Use <watch-ref> to define the Git references to watch for commits.
 Use --watch-regex=<regex> instead to specify references that matches the Regex
+Use --watch-glob=<glob> to specify references that match a Git style glob, i.e. `refs/heads/*`. Like in a Git
+refspec, `*` also matches `/`, so `refs/heads/*` matches `refs/heads/feature/x` too.
+Use --exclude-ref=<reference>, --exclude-regex=<regex> and --exclude-glob=<glob> to exclude references that would
+otherwise be watched. Watched references are merged into each of the references set by --target-reference.
+Use --watch-group=<target>:<glob> to merge references matching the glob into <target> instead. Each reference is
+merged into the first group that matches it, and groups are matched before the other watch references.
+Watch references, exclusions and target references can also be specified in `[[watch]]` tables in the configuration
+file. Each table is matched after the groups from --watch-group and before the other watch references. Tables
+without `targets` are merged into the references set by --target-reference. Exclusions from the command line
+apply to every table.
 
 Options:
-  --remote=<remote>                 Name of the remote to use. [default: origin]
+  --remote=<remote>                 Name of the remote to fetch from. [default: origin]
   --notes-namespace=<namespace>     Metadata generated by fusionner is stored as Git notes.
                                     Namespace for the Git notes that fusionner will create. [default: fusionner]
-  --target-reference=<reference>    The target reference for references to be meged against. [default: HEAD]
+  --target-reference=<reference>    The target reference for references to be meged against. Can be specified
+                                    multiple times to merge against several target references. [default: HEAD]
+  --derive-target=<template>        Derive the target reference from the captures of the first --watch-regex
+                                    that matches a watched reference, i.e. `refs/heads/$target` with the regex
+                                    `^refs/heads/for/(?P<target>[^/]+)/.*$`. References where no target can be
+                                    derived are merged into the references set by --target-reference.
+  --refresh-cache                   Fetch into the `reference_repository` set in the configuration file, creating
+                                    it if necessary, and exit. The watch references are not needed.
   --log-level=<log-level>           The default log level is `info`.
                                     Can be set to `trace`, `debug`, `info`, `warn`, or `error` [default: info]
   -h --help                         Show this screen.
@@ -59,8 +78,15 @@ Options:
 struct Args {
     arg_configuration_file: String,
     flag_watch_regex: Vec<String>,
+    flag_watch_glob: Vec<String>,
+    flag_exclude_ref: Vec<String>,
+    flag_exclude_regex: Vec<String>,
+    flag_exclude_glob: Vec<String>,
+    flag_watch_group: Vec<String>,
+    flag_derive_target: Option<String>,
+    flag_refresh_cache: bool,
     flag_log_level: String,
-    flag_target_reference: String,
+    flag_target_reference: Vec<String>,
     flag_remote: String,
     flag_notes_namespace: String,
     arg_watch_ref: Vec<String>,
@@ -73,10 +99,105 @@ pub struct Config {
     pub repository: RepositoryConfiguration,
     /// Interval, in seconds, between loops to look for new commits. Defaults to 30
     pub interval: Option<u64>,
+    /// Groups of references to watch, and their target references. These are in addition to the references
+    /// specified on the command line
+    #[serde(default)]
+    pub watch: Vec<WatchConfiguration>,
+    /// Maximum number of merges to perform in a single loop. Every target reference a watched reference is merged
+    /// into counts as a merge. References that are not merged into all of their targets are carried over to the
+    /// next loop, ahead of the others. Defaults to no limit
+    pub max_merges: Option<usize>,
+    /// Push the notes and every merge reference created in a loop with a single push at the end of the loop,
+    /// instead of pushing after every watched reference is merged. Defaults to `false`
+    #[serde(default)]
+    pub batch_push: bool,
+    /// Push merge references and notes with a lease instead of force pushing them, so that references changed
+    /// by someone else are reported instead of being overwritten. Defaults to `false`
+    #[serde(default)]
+    pub push_with_lease: bool,
 }
 
 const DEFAULT_INTERVAL: u64 = 30;
 
+/// Tracks the number of merges performed in a loop, and the references deferred to the next loop
+struct MergeBudget {
+    max_merges: Option<usize>,
+    merges: usize,
+    carried_over: HashSet<String>,
+    deferred: HashSet<String>,
+}
+
+impl MergeBudget {
+    fn new(max_merges: Option<usize>) -> MergeBudget {
+        MergeBudget {
+            max_merges,
+            merges: 0,
+            carried_over: HashSet::new(),
+            deferred: HashSet::new(),
+        }
+    }
+
+    /// Reset the budget for a new loop. References deferred in the previous loop are carried over.
+    fn start_loop(&mut self) {
+        self.merges = 0;
+        self.carried_over = std::mem::replace(&mut self.deferred, HashSet::new());
+    }
+
+    /// Returns the number of merges left in this loop, or `None` if there is no limit
+    fn remaining(&self) -> Option<usize> {
+        self.max_merges.map(|max_merges| max_merges.saturating_sub(self.merges))
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.remaining() == Some(0)
+    }
+}
+
+/// A watched reference and a target reference checked in a loop, and the references that have to be pushed before
+/// the pair counts as checked
+struct CheckedPair {
+    reference: String,
+    oid: git2::Oid,
+    target_ref: String,
+    target_oid: git2::Oid,
+    push_references: Vec<String>,
+}
+
+/// The OIDs of the watched references and target references that were checked in previous loops, including the
+/// pairs that were skipped or failed to merge. Pairs whose OIDs have not changed since are not fetched or checked
+/// again.
+struct CheckedPairs {
+    checked: HashMap<(String, String), (git2::Oid, git2::Oid)>,
+}
+
+impl CheckedPairs {
+    fn new() -> CheckedPairs {
+        CheckedPairs {
+            checked: HashMap::new(),
+        }
+    }
+
+    fn is_unchanged(&self, reference: &str, oid: git2::Oid, target_ref: &str, target_oid: git2::Oid) -> bool {
+        self.checked
+            .get(&(reference.to_string(), target_ref.to_string()))
+            .map_or(false, |&checked| checked == (oid, target_oid))
+    }
+
+    fn record(&mut self, reference: &str, oid: git2::Oid, target_ref: &str, target_oid: git2::Oid) {
+        self.checked
+            .insert((reference.to_string(), target_ref.to_string()), (oid, target_oid));
+    }
+
+    /// Record `pair` if all of its references are in `updated`, the references that were pushed successfully.
+    /// Otherwise, it is checked again in the next loop.
+    fn record_pushed(&mut self, pair: CheckedPair, updated: &[String]) {
+        if pair.push_references.iter().all(|reference| updated.contains(reference)) {
+            self.checked
+                .insert((pair.reference, pair.target_ref), (pair.oid, pair.target_oid));
+        }
+    }
+}
+
 impl Config {
     /// Read configuration from a TOML file
     pub fn read_config(path: &str) -> Result<Config, String> {
@@ -126,24 +247,41 @@ fn main() {
             })
             .unwrap();
         debug!("Configuration parsed {:?}", config);
-
-        let watch_refs = WatchReferences::new(args.arg_watch_ref.as_slice(), args.flag_watch_regex.as_slice())
-            .map_err(|err| panic!("Failed to compile watch reference regex: {:?}", err))
+        config
+            .repository
+            .validate_refspecs()
+            .map_err(|err| panic!("Invalid refspec in configuration file: {}", err))
             .unwrap();
 
-        info!("Watch Referemces: {:?}", watch_refs);
+        return_code = if args.flag_refresh_cache {
+            match git::Repository::refresh_reference_repository(&config.repository, Rc::new(ProgressSummary)) {
+                Ok(()) => 0,
+                Err(err) => {
+                    error!("Failed to refresh the reference repository: {}", err);
+                    1
+                }
+            }
+        } else {
+            let watch_groups = watch_groups(&config, &args)
+                .map_err(|err| panic!("Failed to compile watch references: {}", err))
+                .unwrap();
+            if watch_groups.is_empty() {
+                panic!("No watch references specified on the command line or in the configuration file");
+            }
 
-        return_code = match process(
-            &config,
-            &watch_refs,
-            &Some(args.flag_target_reference),
-            &Some(args.flag_remote),
-            &Some(args.flag_notes_namespace),
-        ) {
-            Ok(_) => 0,
-            Err(err) => {
-                error!("Error: {}", err);
-                1
+            info!("Watch Groups: {:?}", watch_groups);
+
+            match process(
+                &config,
+                &watch_groups,
+                &Some(args.flag_remote),
+                &Some(args.flag_notes_namespace),
+            ) {
+                Ok(_) => 0,
+                Err(err) => {
+                    error!("Error: {}", err);
+                    1
+                }
             }
         };
     }
@@ -151,17 +289,66 @@ fn main() {
     std::process::exit(return_code);
 }
 
+/// Build the watch groups from the `--watch-group` arguments, followed by the `[[watch]]` tables from the
+/// configuration file and finally a group with the rest of the watch references from the command line.
+/// Exclusions from the command line are applied to every group.
+fn watch_groups(config: &Config, args: &Args) -> Result<Vec<WatchGroup>, String> {
+    let mut configurations: Vec<WatchConfiguration> = vec![];
+    for group in &args.flag_watch_group {
+        let separator = group
+            .find(':')
+            .ok_or_else(|| format!("Invalid watch group `{}`: expected <target>:<glob>", group))?;
+        let (target, glob) = (&group[0..separator], &group[(separator + 1)..]);
+
+        match configurations.iter().position(|c| c.targets[0] == target) {
+            Some(index) => configurations[index].globs.push(glob.to_string()),
+            None => configurations.push(WatchConfiguration {
+                globs: vec![glob.to_string()],
+                targets: vec![target.to_string()],
+                ..Default::default()
+            }),
+        }
+    }
+
+    configurations.extend(config.watch.iter().cloned());
+    configurations.push(WatchConfiguration {
+        exacts: args.arg_watch_ref.clone(),
+        regexes: args.flag_watch_regex.clone(),
+        globs: args.flag_watch_glob.clone(),
+        derive_target: args.flag_derive_target.clone(),
+        ..Default::default()
+    });
+
+    let mut groups = vec![];
+    for mut configuration in configurations.into_iter().filter(|c| c.has_inclusions()) {
+        configuration
+            .exclude_exacts
+            .extend(args.flag_exclude_ref.iter().cloned());
+        configuration
+            .exclude_regexes
+            .extend(args.flag_exclude_regex.iter().cloned());
+        configuration
+            .exclude_globs
+            .extend(args.flag_exclude_glob.iter().cloned());
+
+        let group = WatchGroup::from_configuration(&configuration, &args.flag_target_reference);
+        groups.push(map_err!(group)?);
+    }
+    Ok(groups)
+}
+
 fn process(
     config: &Config,
-    watch_refs: &WatchReferences,
-    target_ref: &Option<String>,
+    watch_groups: &[WatchGroup],
     remote_name: &Option<String>,
     notes_namespace: &Option<String>,
 ) -> Result<(), String> {
     // Create our structs
-    let repo = map_err!(git::Repository::clone_or_open(&config.repository))?;
+    let repo = map_err!(git::Repository::clone_or_open_with_progress(
+        &config.repository,
+        Rc::new(ProgressSummary)
+    ))?;
     let remote_name = to_option_str(&remote_name);
-    let mut remote = map_err!(repo.remote(remote_name))?;
     let mut merger = map_err!(merger::Merger::new(
         &repo,
         remote_name,
@@ -169,28 +356,54 @@ fn process(
         None,
     ))?;
 
+    merger.set_push_with_lease(config.push_with_lease);
+    if let Some(ref push_remote) = config.repository.push_remote {
+        map_err!(merger.set_push_remote(push_remote))?;
+    }
+
     // Add the necessary refspecs
     map_err!(merger.add_note_refspecs())?;
-    map_err!(merger::MergeReferenceNamer::add_default_refspecs(&remote))?;
+    map_err!(merger::MergeReferenceNamer::add_default_refspecs(merger.push_remote()))?;
 
-    map_err!(remote.add_refspecs(
+    map_err!(merger.remote().add_refspecs(
         &utils::as_str_slice(&config.repository.fetch_refspecs,),
         git2::Direction::Fetch,
     ))?;
-    map_err!(remote.add_refspecs(
+    map_err!(merger.push_remote().add_refspecs(
         &utils::as_str_slice(&config.repository.push_refspecs),
         git2::Direction::Push,
     ))?;
 
-    let target_ref = map_err!(remote.resolve_target_ref(to_option_str(&target_ref)))?;
+    let mut target_refs = vec![];
+    for group in watch_groups {
+        let mut group_target_refs = vec![];
+        for target_ref in &group.target_references {
+            let target_ref = merger.remote_mut().resolve_target_ref(Some(target_ref));
+            group_target_refs.push(map_err!(target_ref)?);
+        }
+        target_refs.push(group_target_refs);
+    }
 
     // Setup intervals
     let interal_seconds = config.interval.or(Some(DEFAULT_INTERVAL)).unwrap();
     let interval = std::time::Duration::from_secs(interal_seconds);
 
+    let mut budget = MergeBudget::new(config.max_merges);
+    let mut checked = CheckedPairs::new();
     loop {
-        if let Err(e) = process_loop(&mut remote, &mut merger, watch_refs, &target_ref) {
-            warn!("Error: {:?}", e);
+        budget.start_loop();
+        match process_loop(
+            &repo,
+            &mut merger,
+            watch_groups,
+            &target_refs,
+            &mut budget,
+            &mut checked,
+            config.batch_push,
+        ) {
+            Err(ref e) if git::is_timeout(e) => warn!("Remote operation timed out: {}", e),
+            Err(e) => warn!("Error: {:?}", e),
+            Ok(()) => {}
         }
         info!("Sleeping for {:?} seconds", interal_seconds);
         std::thread::sleep(interval);
@@ -198,38 +411,51 @@ fn process(
 }
 
 fn process_loop(
-    remote: &mut git::Remote,
+    repo: &git::Repository,
     merger: &mut merger::Merger,
-    watch_refs: &WatchReferences,
-    target_ref: &str,
+    watch_groups: &[WatchGroup],
+    target_refs: &[Vec<String>],
+    budget: &mut MergeBudget,
+    checked: &mut CheckedPairs,
+    batch_push: bool,
 ) -> Result<(), git2::Error> {
     info!("Retrieving remote heads");
-    let remote_ls = return_if_empty!(remote.remote_ls()?, git_err!("No remote references found"));
+    merger.remote_mut().clear_remote_ls_cache();
+    merger.push_remote_mut().clear_remote_ls_cache();
+    let remote_ls = return_if_empty!(merger.remote_mut().remote_ls()?, git_err!("No remote references found"));
 
     info!("{} remote heads found", remote_ls.len());
     debug!("{:?}", remote_ls);
 
-    let watch_heads = return_if_empty!(
-        watch_refs.resolve_watch_refs(&remote_ls),
-        git_err!("No matching watched reference found")
-    );
-
-    info!("{} remote references matched watch references", watch_heads.len());
-    debug!("{:?}", watch_heads);
-
-    info!("Fetching matched remotes and target reference");
-    let mut fetch_refs = watch_heads.iter().map(|s| s.as_str()).collect::<Vec<&str>>();
-    fetch_refs.push(target_ref);
-
-    {
-        let forced_fetch_refs: Vec<String> = fetch_refs.iter().map(|s| git::RefspecStr::as_forced(s)).collect();
-        let forced_fetch_refs_slice: Vec<&str> = forced_fetch_refs.iter().map(|s| &**s).collect();
+    let group_heads = WatchGroup::resolve_watch_groups(watch_groups, &remote_ls);
+    if group_heads.iter().all(|heads| heads.is_empty()) {
+        return Err(git_err!("No matching watched reference found"));
+    }
 
-        remote.fetch(&forced_fetch_refs_slice)?;
+    info!("Resolving target references for watched references");
+    let group_reference_targets: Vec<HashMap<&str, Vec<String>>> = watch_groups
+        .iter()
+        .zip(group_heads.iter())
+        .zip(target_refs)
+        .map(|((group, watch_heads), target_refs)| {
+            resolve_reference_targets(&group.watch_refs, watch_heads, target_refs, &remote_ls)
+        })
+        .collect();
+    debug!("{:?}", group_reference_targets);
+
+    let mut references: HashSet<&str> = HashSet::new();
+    for reference_targets in &group_reference_targets {
+        references.extend(reference_targets.keys().cloned());
+        references.extend(
+            reference_targets
+                .values()
+                .flat_map(|targets| targets.iter().map(|s| s.as_str())),
+        );
     }
+    let references: Vec<&str> = references.into_iter().collect();
 
     info!("Resolving references and oid");
-    let oids: HashMap<String, git2::Oid> = resolve_oids(fetch_refs.as_slice(), remote_ls.as_slice())
+    let oids: HashMap<String, git2::Oid> = resolve_oids(references.as_slice(), &remote_ls)
         .iter()
         .filter(|&(reference, oid)| match *oid {
             None => {
@@ -243,18 +469,276 @@ fn process_loop(
     let oids = return_if_empty!(oids, git_err!("No valid OIDs resolved"));
     debug!("{:?}", oids);
 
-    info!("Resolving reference and OID for target reference");
-    let target_oid =
-        resolve_oid(target_ref, &remote_ls).ok_or_else(|| git_err!("Unable to find OID for target reference"))?;
+    // Only the watched references with a target that changed since they were last checked, and their targets,
+    // need to be fetched
+    let mut changed_refs: HashSet<&str> = HashSet::new();
+    for reference_targets in &group_reference_targets {
+        for (reference, targets) in reference_targets {
+            let oid = match oids.get(*reference) {
+                Some(oid) => *oid,
+                None => continue,
+            };
+            let changed_targets: Vec<&str> = targets
+                .iter()
+                .filter(|target_ref| match oids.get(*target_ref) {
+                    Some(target_oid) => !checked.is_unchanged(reference, oid, target_ref, *target_oid),
+                    None => false,
+                })
+                .map(|target_ref| target_ref.as_str())
+                .collect();
+            if !changed_targets.is_empty() {
+                changed_refs.insert(reference);
+                changed_refs.extend(changed_targets);
+            }
+        }
+    }
+
+    if changed_refs.is_empty() {
+        info!("No watched or target references have changed since the last loop");
+        merger.remote_mut().disconnect();
+        merger.push_remote_mut().disconnect();
+        return Ok(());
+    }
+
+    // Fetch everything needed for this loop at once, since every fetch opens a new connection. References whose
+    // commits are already in the repository do not need to be fetched again.
+    info!("Fetching notes, changed remotes and target references");
+    let fetch_refs: Vec<&str> = changed_refs
+        .into_iter()
+        .filter(|reference| repo.repository.find_commit(oids[*reference]).is_err())
+        .collect();
+    debug!("{:?}", fetch_refs);
+
+    {
+        let mut forced_fetch_refs: Vec<String> = fetch_refs.iter().map(|s| git::RefspecStr::as_forced(s)).collect();
+        if merger.has_push_remote() {
+            merger.fetch_notes()?;
+        } else {
+            forced_fetch_refs.push(merger.notes_fetch_refspec());
+        }
+        let forced_fetch_refs_slice: Vec<&str> = forced_fetch_refs.iter().map(|s| &**s).collect();
 
-    info!("Fetching notes for commits");
-    merger.fetch_notes()?;
+        if !forced_fetch_refs_slice.is_empty() {
+            merger.remote_mut().fetch(&forced_fetch_refs_slice)?;
+        }
+    }
 
-    let mut push_references = HashSet::<String>::new();
-    for (reference, oid) in oids {
-        match merger.check_and_merge(oid, target_oid, &reference, target_ref, true) {
-            Ok((merge, _should_merge)) => {
-                push_references.insert(merge.merge_reference);
+    let mut pending: Vec<CheckedPair> = vec![];
+    for ((group, reference_targets), target_refs) in
+        watch_groups.iter().zip(group_reference_targets.iter()).zip(target_refs)
+    {
+        if reference_targets.is_empty() {
+            continue;
+        }
+
+        info!(
+            "{} remote references matched watch references for {:?}",
+            reference_targets.len(),
+            target_refs
+        );
+
+        match process_group(
+            repo,
+            merger,
+            &group.watch_refs,
+            reference_targets,
+            &oids,
+            budget,
+            checked,
+            !batch_push,
+        ) {
+            Ok(pairs) => pending.extend(pairs),
+            Err(e) => warn!("Error processing references for {:?}: {:?}", target_refs, e),
+        }
+    }
+
+    let push_references: HashSet<&str> = pending
+        .iter()
+        .flat_map(|pair| pair.push_references.iter().map(|s| s.as_str()))
+        .collect();
+    if push_references.is_empty() {
+        for pair in pending {
+            checked.record_pushed(pair, &[]);
+        }
+    } else {
+        let push_references: Vec<String> = push_references.into_iter().map(|s| s.to_string()).collect();
+        match merger.push(&push_references) {
+            Ok(report) => {
+                log_push_report(&report);
+                for pair in pending {
+                    checked.record_pushed(pair, &report.updated);
+                }
+            }
+            Err(git::PushError::LeaseMismatch(mismatches)) => log_lease_mismatches(&mismatches),
+            Err(git::PushError::Git(e)) => return Err(e),
+        }
+    }
+
+    if !budget.deferred.is_empty() {
+        info!(
+            "{} merges performed, {} references deferred to the next loop",
+            budget.merges,
+            budget.deferred.len()
+        );
+    }
+
+    merger.remote_mut().disconnect();
+    merger.push_remote_mut().disconnect();
+    Ok(())
+}
+
+/// Resolve the target references for each of the watched references of a group
+fn resolve_reference_targets<'a>(
+    watch_refs: &WatchReferences,
+    watch_heads: &'a HashSet<String>,
+    target_refs: &[String],
+    remote_ls: &[git::RemoteHead],
+) -> HashMap<&'a str, Vec<String>> {
+    let mut reference_targets: HashMap<&str, Vec<String>> = HashMap::new();
+    for reference in watch_heads {
+        match watch_refs.derive_target_ref(reference, remote_ls) {
+            Ok(Some(target_ref)) => {
+                reference_targets.insert(reference, vec![target_ref]);
+            }
+            Ok(None) => {
+                reference_targets.insert(reference, target_refs.to_vec());
+            }
+            Err(e) => {
+                error!("Error resolving target reference for {}: {:?}", reference, e);
+            }
+        }
+    }
+    reference_targets
+}
+
+fn process_group(
+    repo: &git::Repository,
+    merger: &mut merger::Merger,
+    watch_refs: &WatchReferences,
+    reference_targets: &HashMap<&str, Vec<String>>,
+    oids: &HashMap<String, git2::Oid>,
+    budget: &mut MergeBudget,
+    checked: &mut CheckedPairs,
+    push: bool,
+) -> Result<Vec<CheckedPair>, git2::Error> {
+    let mut references: Vec<(&str, git2::Oid)> = vec![];
+    let mut changed_targets: HashMap<&str, Vec<(&str, git2::Oid)>> = HashMap::new();
+    for reference in reference_targets.keys() {
+        let oid = match oids.get(*reference) {
+            Some(oid) => *oid,
+            None => continue,
+        };
+        let targets: Vec<(&str, git2::Oid)> = reference_targets[reference]
+            .iter()
+            .filter_map(|target_ref| match oids.get(target_ref) {
+                Some(target_oid) => Some((target_ref.as_str(), *target_oid)),
+                None => {
+                    error!("Unable to find OID for target reference {}", target_ref);
+                    None
+                }
+            })
+            .filter(|&(target_ref, target_oid)| !checked.is_unchanged(reference, oid, target_ref, target_oid))
+            .collect();
+        if targets.is_empty() {
+            debug!("{} ({}) and its targets are unchanged", reference, oid);
+            continue;
+        }
+
+        let skipped = match repo.repository.find_commit(oid) {
+            Ok(commit) => match watch_refs.skip_reason(&commit) {
+                Some(reason) => {
+                    info!("Skipping {} ({}): {}", reference, oid, reason);
+                    true
+                }
+                None => false,
+            },
+            Err(e) => {
+                error!("Unable to find commit for {} ({}): {:?}", reference, oid, e);
+                true
+            }
+        };
+        if skipped {
+            // Skipped references are not checked again until they or their targets change
+            for &(target_ref, target_oid) in &targets {
+                checked.record(reference, oid, target_ref, target_oid);
+            }
+            continue;
+        }
+        references.push((reference, oid));
+        changed_targets.insert(reference, targets);
+    }
+
+    // References deferred from the previous loop go first
+    watch_refs.prioritise(&repo.repository, &mut references);
+    references.sort_by_key(|&(reference, _)| !budget.carried_over.contains(reference));
+
+    // Pairs checked without pushing, which are recorded once their references are pushed
+    let mut pending = vec![];
+    for (reference, oid) in references {
+        if budget.is_exhausted() {
+            debug!("Merge budget exhausted, deferring {} to the next loop", reference);
+            budget.deferred.insert(reference.to_string());
+            continue;
+        }
+
+        // Only check as many targets as the budget allows. The rest are checked in the next loop, because they are
+        // not recorded as checked.
+        let targets = &changed_targets[reference];
+        let targets = match budget.remaining() {
+            Some(remaining) if remaining < targets.len() => {
+                debug!(
+                    "Merge budget exhausted, deferring {} targets of {} to the next loop",
+                    targets.len() - remaining,
+                    reference
+                );
+                budget.deferred.insert(reference.to_string());
+                &targets[..remaining]
+            }
+            _ => &targets[..],
+        };
+        match merger.check_and_merge_targets(oid, reference, targets, push) {
+            Ok((results, report)) => {
+                if let Some(ref report) = report {
+                    log_push_report(report);
+                }
+                for ((target_ref, result), &(_, target_oid)) in results.into_iter().zip(targets.iter()) {
+                    let mut push_references = vec![];
+                    match result {
+                        Ok((merge, merger::ShouldMergeResult::Merge(_))) => {
+                            budget.merges += 1;
+                            push_references.push(merger.notes_reference());
+                            push_references.push(merge.merge_reference);
+                        }
+                        Ok((_, merger::ShouldMergeResult::ExistingMergeInDifferentTargetReference { .. })) => {
+                            push_references.push(merger.notes_reference());
+                        }
+                        Ok((_, merger::ShouldMergeResult::ExistingMergeInSameTargetReference(_))) => {}
+                        // Conflicts are not attempted again until the references change
+                        Err(merger::MergeError::Conflict) => {
+                            info!("{} ({}) has conflicts with {}", reference, oid, target_ref);
+                        }
+                        // Other errors, i.e. failing to write the notes, are retried in the next loop
+                        Err(e) => {
+                            error!("Error processing {} ({}) into {}: {:?}", reference, oid, target_ref, e);
+                            continue;
+                        }
+                    }
+
+                    let pair = CheckedPair {
+                        reference: reference.to_string(),
+                        oid,
+                        target_ref,
+                        target_oid,
+                        push_references,
+                    };
+                    match report {
+                        Some(ref report) => checked.record_pushed(pair, &report.updated),
+                        None => pending.push(pair),
+                    }
+                }
+            }
+            Err(merger::MergeError::Push(git::PushError::LeaseMismatch(mismatches))) => {
+                log_lease_mismatches(&mismatches)
             }
             Err(e) => {
                 error!("Error processing {} ({}): {:?}", reference, oid, e);
@@ -262,8 +746,64 @@ fn process_loop(
         }
     }
 
-    remote.disconnect();
-    Ok(())
+    Ok(pending)
+}
+
+fn log_lease_mismatches(mismatches: &[git::LeaseMismatch]) {
+    for mismatch in mismatches {
+        error!(
+            "Not pushing {}: expected {} on the remote, found {:?}",
+            mismatch.reference, mismatch.expected, mismatch.actual
+        );
+    }
+}
+
+fn log_push_report(report: &git::PushReport) {
+    if report.is_success() {
+        info!("{} references pushed", report.updated.len());
+        return;
+    }
+
+    for &(ref reference, ref status) in &report.rejected {
+        error!("Remote rejected {}: {}", reference, status);
+    }
+    error!(
+        "{} references pushed, {} references rejected",
+        report.updated.len(),
+        report.rejected.len()
+    );
+}
+
+/// Logs periodic summaries of clones and fetches at the info level. Other progress is passed on to
+/// `git::LogProgressObserver`
+struct ProgressSummary;
+
+impl git::ProgressObserver for ProgressSummary {
+    fn interval(&self) -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn transfer_progress(&self, progress: &git::TransferProgress) {
+        if progress.total_objects == 0 {
+            return;
+        }
+        info!(
+            "Received {}/{} objects ({} KiB), resolved {}/{} deltas",
+            progress.received_objects,
+            progress.total_objects,
+            progress.received_bytes / 1024,
+            progress.indexed_deltas,
+            progress.total_deltas
+        );
+    }
+
+    fn sideband_progress(&self, message: &str) {
+        git::LogProgressObserver.sideband_progress(message);
+    }
+
+    fn update_tip(&self, reference: &str, old: git2::Oid, new: git2::Oid) {
+        git::LogProgressObserver.update_tip(reference, old, new);
+    }
 }
 
 // TODO: Support logging to file/stderr/etc.
@@ -314,28 +854,163 @@ fn to_option_str(opt: &Option<String>) -> Option<&str> {
 
 #[cfg(test)]
 mod tests {
-    use fusionner::RepositoryConfiguration;
-    use {Config, Password};
+    use fusionner::{
+        Credentials, RemoteConfiguration, RepositoryConfiguration, RetryConfiguration, TlsVerification,
+        WatchConfiguration, WatchOrder,
+    };
+    use {CheckedPair, CheckedPairs, Config, MergeBudget, Password};
 
     #[test]
     fn config_reading_smoke_test() {
         let config = not_err!(Config::read_config("tests/fixtures/config.toml"));
         let expected_config = Config {
             interval: Some(60),
+            max_merges: Some(10),
+            batch_push: true,
+            push_with_lease: true,
             repository: RepositoryConfiguration {
                 uri: "https://github.com/lawliet89/fusionner.git".to_string(),
                 checkout_path: "target/test_repo".to_string(),
                 fetch_refspecs: vec!["+refs/pull/*:refs/remotes/origin/pull/*".to_string()],
                 push_refspecs: vec![],
-                username: Some("john doe".to_string()),
-                password: Some(Password::new("P@ssword!")),
-                key: Some("/home/user/.ssh/key".to_string()),
-                key_passphrase: Some(Password::new("Such a password")),
+                credentials: Credentials {
+                    username: Some("john doe".to_string()),
+                    password: Some(Password::new("P@ssword!")),
+                    password_file: None,
+                    password_env: None,
+                    token_only: false,
+                    key: Some("/home/user/.ssh/key".to_string()),
+                    key_env: None,
+                    key_passphrase: Some(Password::new("Such a password")),
+                    key_passphrase_file: None,
+                    key_passphrase_env: None,
+                },
                 signature_name: Some("Foobar".to_string()),
                 signature_email: Some("foo@bar.xyz".to_string()),
+                bare: true,
+                reference_repository: Some("/var/cache/fusionner/objects.git".to_string()),
+                depth: Some(100),
+                deepen_by: Some(200),
+                reclone_on_corruption: true,
+                remotes: vec![(
+                    "mirror".to_string(),
+                    RemoteConfiguration {
+                        uri: "https://ci.example.com/fusionner.git".to_string(),
+                        credentials: Credentials {
+                            password_env: Some("MIRROR_TOKEN".to_string()),
+                            token_only: true,
+                            ..Default::default()
+                        },
+                    },
+                )]
+                .into_iter()
+                .collect(),
+                push_remote: Some("mirror".to_string()),
+                known_hosts: Some("/home/user/.ssh/known_hosts".to_string()),
+                host_key_fingerprints: vec![(
+                    "github.com".to_string(),
+                    vec!["sha1:2fd4e1c67a2d28fced849ee1bb76e7391b93eb12".to_string()],
+                )]
+                .into_iter()
+                .collect(),
+                trust_on_first_use: None,
+                proxy: Some("http://proxy.example.com:3128".to_string()),
+                ca_bundle: Some("/etc/ssl/certs/internal-ca.pem".to_string()),
+                tls_verification: Some(TlsVerification::Verify),
+                connect_timeout: Some(30),
+                operation_timeout: Some(600),
+                retry: RetryConfiguration {
+                    max_retries: 5,
+                    initial_backoff_ms: 500,
+                    ..Default::default()
+                },
             },
+            watch: vec![
+                WatchConfiguration {
+                    exacts: vec!["refs/heads/develop".to_string()],
+                    regexes: vec!["^refs/pull/.*$".to_string()],
+                    globs: vec!["refs/heads/feature/**".to_string()],
+                    exclude_exacts: vec!["refs/heads/master".to_string()],
+                    exclude_regexes: vec!["^refs/heads/wip/.*$".to_string()],
+                    exclude_globs: vec!["refs/heads/feature/wip-*".to_string()],
+                    derive_target: None,
+                    targets: vec![],
+                    max_age: Some(2592000),
+                    skip_markers: vec!["[skip fusionner]".to_string()],
+                    skip_trailers: vec!["Fusionner: skip".to_string()],
+                    order: None,
+                    priority_regexes: vec![],
+                    priority_globs: vec![],
+                },
+                WatchConfiguration {
+                    regexes: vec!["^refs/heads/for/(?P<target>[^/]+)/.*$".to_string()],
+                    derive_target: Some("refs/heads/$target".to_string()),
+                    targets: vec!["refs/heads/master".to_string(), "refs/heads/release-2.x".to_string()],
+                    order: Some(WatchOrder::Recent),
+                    priority_globs: vec!["refs/heads/for/*/hotfix-*".to_string()],
+                    ..Default::default()
+                },
+            ],
         };
 
         assert_eq!(config, expected_config);
     }
+
+    #[test]
+    fn changed_pairs_are_checked_again() {
+        let oid = git2::Oid::from_str("2fd4e1c67a2d28fced849ee1bb76e7391b93eb12").unwrap();
+        let target_oid = git2::Oid::from_str("de9f2c7fd25e1b3afad3e85a0bd17d9b100db4b3").unwrap();
+
+        let mut checked = CheckedPairs::new();
+        assert!(!checked.is_unchanged("refs/heads/topic", oid, "refs/heads/master", target_oid));
+
+        checked.record("refs/heads/topic", oid, "refs/heads/master", target_oid);
+        assert!(checked.is_unchanged("refs/heads/topic", oid, "refs/heads/master", target_oid));
+        assert!(!checked.is_unchanged("refs/heads/topic", target_oid, "refs/heads/master", target_oid));
+        assert!(!checked.is_unchanged("refs/heads/topic", oid, "refs/heads/master", oid));
+        assert!(!checked.is_unchanged("refs/heads/topic", oid, "refs/heads/develop", target_oid));
+    }
+
+    #[test]
+    fn pairs_are_checked_once_pushed() {
+        let oid = git2::Oid::from_str("2fd4e1c67a2d28fced849ee1bb76e7391b93eb12").unwrap();
+        let target_oid = git2::Oid::from_str("de9f2c7fd25e1b3afad3e85a0bd17d9b100db4b3").unwrap();
+        let pair = || CheckedPair {
+            reference: "refs/heads/topic".to_string(),
+            oid,
+            target_ref: "refs/heads/master".to_string(),
+            target_oid,
+            push_references: vec!["refs/notes/fusionner".to_string(), "refs/fusionner/topic".to_string()],
+        };
+
+        let mut checked = CheckedPairs::new();
+        checked.record_pushed(pair(), &["refs/notes/fusionner".to_string()]);
+        assert!(!checked.is_unchanged("refs/heads/topic", oid, "refs/heads/master", target_oid));
+
+        checked.record_pushed(
+            pair(),
+            &["refs/fusionner/topic".to_string(), "refs/notes/fusionner".to_string()],
+        );
+        assert!(checked.is_unchanged("refs/heads/topic", oid, "refs/heads/master", target_oid));
+    }
+
+    #[test]
+    fn merge_budget_counts_every_target() {
+        let mut budget = MergeBudget::new(Some(3));
+        assert_eq!(Some(3), budget.remaining());
+
+        budget.merges += 2;
+        assert_eq!(Some(1), budget.remaining());
+        assert!(!budget.is_exhausted());
+
+        budget.merges += 1;
+        assert!(budget.is_exhausted());
+
+        budget.deferred.insert("refs/heads/feature".to_string());
+        budget.start_loop();
+        assert_eq!(Some(3), budget.remaining());
+        assert!(budget.carried_over.contains("refs/heads/feature"));
+
+        assert_eq!(None, MergeBudget::new(None).remaining());
+    }
 }
@@ -12,11 +12,12 @@
 //! some `target_oid` and `target_reference`. The former pair usually corresponds to some topic branch while the
 //! latter pair is usually some default branch (i.e. `master`).
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error;
 use std::fmt;
 use std::vec::Vec;
 
-use super::git::{Remote, Repository};
+use super::git::{PushError, PushReport, Remote, Repository};
 use super::utils;
 use super::{git2, git2_raw};
 
@@ -29,8 +30,10 @@ static NOTE_ID: &'static str = "fusionner <https://github.com/lawliet89/fusionne
 pub struct Merger<'repo, 'cb> {
     repository: &'repo Repository<'repo>,
     remote: Remote<'repo>,
+    push_remote: Option<Remote<'repo>>,
     namespace: String,
     merge_reference_namer: MergeReferenceNamer<'cb>,
+    push_with_lease: bool,
 }
 
 /// A `HashMap` of `Merge` where the key is a `String` corresponding to the `target_reference` of the merge.
@@ -66,11 +69,28 @@ pub struct Merge {
     pub merge_reference: String,
 }
 
+/// The result of checking and merging a commit into a single target reference.
+pub type MergeResult = Result<(Merge, ShouldMergeResult), MergeError>;
+
+/// The result of merging into each target reference, as returned by `Merger::check_and_merge_targets`
+pub type TargetMergeResults = Vec<(String, MergeResult)>;
+
 /// Type for callback implementing custom merge reference naming.
 /// The meanings for the input arguments are:
 /// `Fn(reference: &str, target_reference: &str, oid: git2::Oid, target_oid: git2::Oid) -> String`
 pub type MergeReferenceNamerCallback<'a> = Fn(&str, &str, git2::Oid, git2::Oid) -> String + 'a;
 
+/// Errors from `Merger::check_and_merge` and `Merger::check_and_merge_targets`
+#[derive(Debug)]
+pub enum MergeError {
+    /// The commit cannot be merged into the target reference without conflicts
+    Conflict,
+    /// The merge references or the notes could not be pushed, i.e. because they were changed by someone else
+    Push(PushError),
+    /// Error from libgit2
+    Git(git2::Error),
+}
+
 // TODO: Allow customizing of this, but only in code
 /// Customise how the merge references are named.
 pub enum MergeReferenceNamer<'cb> {
@@ -129,14 +149,7 @@ impl<'repo, 'cb> Merger<'repo, 'cb> {
     /// let configuration = RepositoryConfiguration {
     ///     uri: "https://github.com/lawliet89/fusionner.git".to_string(),
     ///     checkout_path: "/tmp/checkout".to_string(),
-    ///     fetch_refspecs: vec![],
-    ///     push_refspecs: vec![],
-    ///     username: None,
-    ///     password: None,
-    ///     key: None,
-    ///     key_passphrase: None,
-    ///     signature_name: None,
-    ///     signature_email: None,
+    ///     ..Default::default()
     /// };
     ///
     /// let repo = Repository::clone_or_open(&configuration).unwrap();
@@ -156,25 +169,74 @@ impl<'repo, 'cb> Merger<'repo, 'cb> {
         Ok(Merger {
             repository: repository,
             remote: remote,
+            push_remote: None,
             namespace: namespace.or_else(|| Some(DEFAULT_NOTES_NAMESPACE)).unwrap().to_string(),
             merge_reference_namer: merge_reference_namer.or(Some(MergeReferenceNamer::Default)).unwrap(),
+            push_with_lease: false,
         })
     }
 
+    /// Push merge references and notes with a lease instead of force pushing them, so that references changed
+    /// by someone else are not overwritten. See `Remote::push_with_lease`.
+    pub fn set_push_with_lease(&mut self, push_with_lease: bool) {
+        self.push_with_lease = push_with_lease;
+    }
+
+    /// Push merge references and notes to a different remote, i.e. a mirror, from the one topic branches and
+    /// target references are fetched from. Notes and existing merge references are then fetched from this remote
+    /// too.
+    pub fn set_push_remote(&mut self, remote_name: &str) -> Result<(), git2::Error> {
+        self.push_remote = Some(self.repository.remote(Some(remote_name))?);
+        Ok(())
+    }
+
+    /// Returns `true` if merge references and notes are pushed to a different remote. See `set_push_remote`.
+    pub fn has_push_remote(&self) -> bool {
+        self.push_remote.is_some()
+    }
+
     /// Add refspecs to a remote to fetch/push commit notes, specific for fusionner
     /// This is based on the `namespace` provided when creating a new `Merger`.
     pub fn add_note_refspecs(&self) -> Result<(), git2::Error> {
         let refspec = format!("+{0}:{0}", self.notes_reference());
 
-        self.remote.add_refspec(&refspec, git2::Direction::Fetch)?;
-        self.remote.add_refspec(&refspec, git2::Direction::Push)
+        self.push_remote().add_refspec(&refspec, git2::Direction::Fetch)?;
+        self.push_remote().add_refspec(&refspec, git2::Direction::Push)
+    }
+
+    /// Returns the remote used by the merger, so that it can be shared with the rest of your code
+    pub fn remote(&self) -> &Remote<'repo> {
+        &self.remote
+    }
+
+    /// Returns the remote used by the merger mutably, so that it can be shared with the rest of your code
+    pub fn remote_mut(&mut self) -> &mut Remote<'repo> {
+        &mut self.remote
+    }
+
+    /// Returns the remote that merge references and notes are pushed to. See `set_push_remote`.
+    pub fn push_remote(&self) -> &Remote<'repo> {
+        self.push_remote.as_ref().unwrap_or(&self.remote)
+    }
+
+    /// Returns the remote that merge references and notes are pushed to mutably. See `set_push_remote`.
+    pub fn push_remote_mut(&mut self) -> &mut Remote<'repo> {
+        match self.push_remote {
+            Some(ref mut push_remote) => push_remote,
+            None => &mut self.remote,
+        }
     }
 
-    /// Fetch notes based on the `namespace` provided when creating a new `Merger` from the remote configured
+    /// Returns the refspec to fetch notes with. Use this to fetch notes along with other references.
+    pub fn notes_fetch_refspec(&self) -> String {
+        format!("+{0}:{0}", self.notes_reference())
+    }
+
+    /// Fetch notes based on the `namespace` provided when creating a new `Merger` from the push remote
     pub fn fetch_notes(&mut self) -> Result<(), git2::Error> {
-        let refs = [format!("+{0}:{0}", self.notes_reference())];
+        let refs = [self.notes_fetch_refspec()];
 
-        self.remote.fetch(&utils::as_str_slice(&refs))
+        self.push_remote_mut().fetch(&utils::as_str_slice(&refs))
     }
 
     /// Find note for commit. Make sure you have fetched them first
@@ -255,7 +317,8 @@ impl<'repo, 'cb> Merger<'repo, 'cb> {
     }
 
     /// Performs a merge and return a `Merge` entry intended for `oid`. You should then add the `Merge` into the
-    /// `Note` for `oid`.
+    /// `Note` for `oid`. Returns `MergeError::Conflict` if the merge has conflicts. Shallow repositories are
+    /// deepened with `Repository::deepen` until the merge base is found.
     ///
     /// In general, you should prefer to use the convenience function `check_and_merge` instead which will do
     /// everything for you. For usage of this function, refer to the source code of `check_and_merge`.
@@ -265,16 +328,26 @@ impl<'repo, 'cb> Merger<'repo, 'cb> {
         target_oid: git2::Oid,
         reference: &str,
         target_reference: &str,
-    ) -> Result<Merge, git2::Error> {
+    ) -> Result<Merge, MergeError> {
         let our_commit = self.repository.repository.find_commit(target_oid)?;
         let their_commit = self.repository.repository.find_commit(oid)?;
 
         debug!("Merging index");
-        let mut merged_index = self.repository
-            .repository
-            .merge_commits(&our_commit, &their_commit, None)?;
+        let repository = &self.repository.repository;
+        let mut merged_index = loop {
+            match repository.merge_commits(&our_commit, &their_commit, None) {
+                Ok(index) => break index,
+                // The merge base might be missing from a shallow repository
+                Err(ref e) if is_missing_object(e) && self.repository.deepen(None)? => continue,
+                Err(ref e) if is_missing_object(e) && repository.is_shallow() => {
+                    let message = format!("{} -- the merge base is missing from the shallow repository", e);
+                    return Err(MergeError::Git(git_err!(&message)));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
         if index_in_conflict(&mut merged_index.iter()) {
-            return Err(git_err!("Index is in conflict after merge -- skipping"));
+            return Err(MergeError::Conflict);
         }
 
         debug!("Writing tree");
@@ -311,7 +384,9 @@ impl<'repo, 'cb> Merger<'repo, 'cb> {
     }
 
     /// Convenience function to check if a merge is required, and merge if needed.
-    /// Will fetch remote merge references. Will push, if desired.
+    /// Will fetch remote merge references, unless their commits are already available locally.
+    /// Will push, if desired, and return the `PushReport` of the push. Failed pushes are returned as
+    /// `MergeError::Push`, so that references changed by someone else can be told apart from other errors.
     /// This function calls both `should_merge` and `merge`.
     pub fn check_and_merge(
         &mut self,
@@ -320,7 +395,7 @@ impl<'repo, 'cb> Merger<'repo, 'cb> {
         reference: &str,
         target_ref: &str,
         push: bool,
-    ) -> Result<(Merge, ShouldMergeResult), git2::Error> {
+    ) -> Result<(Merge, ShouldMergeResult, Option<PushReport>), MergeError> {
         let should_merge = self.should_merge(oid, target_oid, reference, target_ref);
         info!(
             "Merging {} ({}) into {} ({}): {}",
@@ -353,9 +428,7 @@ impl<'repo, 'cb> Merger<'repo, 'cb> {
                 info!("Merge commit is up to date");
                 // Should be safe to unwrap
                 let merge = note.merges[target_ref].clone();
-                // Fetch merge
-                let fetch_refspec = [::git::RefspecStr::as_forced(&merge.merge_reference)];
-                self.remote.fetch(&utils::as_str_slice(&fetch_refspec))?;
+                self.fetch_merge(&merge)?;
                 merge
             }
             ShouldMergeResult::ExistingMergeInDifferentTargetReference {
@@ -368,21 +441,136 @@ impl<'repo, 'cb> Merger<'repo, 'cb> {
                 note.append_with_merge(proposed_merge.clone());
                 info!("Adding note: {:?}", note);
                 self.add_note(&note, oid)?;
-                // Fetch merge
-                let fetch_refspec = [::git::RefspecStr::as_forced(&proposed_merge.merge_reference)];
-                self.remote.fetch(&utils::as_str_slice(&fetch_refspec))?;
+                self.fetch_merge(proposed_merge)?;
                 proposed_merge.clone()
             }
         };
 
-        if push {
-            let refspecs: Vec<String> = push_reference.iter().map(|s| ::git::RefspecStr::as_forced(s)).collect();
-            let refspecs_slice: Vec<&str> = refspecs.iter().map(|s| &**s).collect();
+        let report = if push { Some(self.push(&push_reference)?) } else { None };
+
+        Ok((merge, should_merge, report))
+    }
+
+    /// Convenience function to check and merge a commit into each of the `targets`, which are pairs of
+    /// target references and their OIDs. The `Merge`s for every target are stored in the same `Note`.
+    ///
+    /// Failing to merge into one target does not prevent merging into the others, so the result for
+    /// each target reference is returned separately. Will push the notes and all new merge references at once,
+    /// if desired, and return the `PushReport` of the push.
+    pub fn check_and_merge_targets(
+        &mut self,
+        oid: git2::Oid,
+        reference: &str,
+        targets: &[(&str, git2::Oid)],
+        push: bool,
+    ) -> Result<(TargetMergeResults, Option<PushReport>), MergeError> {
+        let mut push_reference = vec![self.notes_reference()];
+
+        let results: TargetMergeResults = targets
+            .iter()
+            .map(|&(target_ref, target_oid)| {
+                let result = self
+                    .check_and_merge(oid, target_oid, reference, target_ref, false)
+                    .map(|(merge, should_merge, _)| (merge, should_merge));
+                if let Ok((ref merge, ShouldMergeResult::Merge(_))) = result {
+                    push_reference.push(merge.merge_reference.to_string());
+                }
+                (target_ref.to_string(), result)
+            })
+            .collect();
+
+        let report = if push { Some(self.push(&push_reference)?) } else { None };
+
+        Ok((results, report))
+    }
+
+    /// Fetch the merge reference, if the merge commit is not available locally
+    fn fetch_merge(&mut self, merge: &Merge) -> Result<(), git2::Error> {
+        let merge_oid = git2::Oid::from_str(&merge.merge_oid)?;
+        if self.repository.repository.find_commit(merge_oid).is_ok() {
+            debug!("Merge commit {} is available locally", merge_oid);
+            return Ok(());
+        }
+
+        let fetch_refspec = [::git::RefspecStr::as_forced(&merge.merge_reference)];
+        self.push_remote_mut().fetch(&utils::as_str_slice(&fetch_refspec))
+    }
+
+    /// Force push the references to the push remote. Use this to push the notes reference along with the
+    /// merge references of several calls to `check_and_merge` or `check_and_merge_targets` at once.
+    ///
+    /// If `set_push_with_lease` is enabled, references changed by someone else are reported with
+    /// `PushError::LeaseMismatch` instead. Merge references that were not pushed or fetched since the `Merger` was
+    /// created must be missing on the remote, or point to a merge commit recorded in the notes.
+    /// References rejected by the remote are listed in the `PushReport`.
+    pub fn push(&mut self, references: &[String]) -> Result<PushReport, PushError> {
+        let refspecs: Vec<String> = references.iter().map(|s| ::git::RefspecStr::as_forced(s)).collect();
+        let refspecs_slice: Vec<&str> = refspecs.iter().map(|s| &**s).collect();
+        if self.push_with_lease {
+            self.lease_merge_references(references)?;
+            info!("Pushing with lease to {:?}", refspecs);
+            self.push_remote_mut().push_with_lease(&refspecs_slice)
+        } else {
             info!("Pushing to {:?}", refspecs);
-            self.remote.push(&refspecs_slice)?;
+            Ok(self.push_remote_mut().push(&refspecs_slice)?)
+        }
+    }
+
+    /// Set the leases of the merge references without one. A merge reference that exists on the remote is expected
+    /// to point to a merge commit recorded for it in the notes. Otherwise, it was changed by someone else, and the
+    /// lease expects the zero OID so that it is not overwritten.
+    fn lease_merge_references(&mut self, references: &[String]) -> Result<(), git2::Error> {
+        let notes_reference = self.notes_reference();
+        let references: Vec<&String> = references
+            .iter()
+            .filter(|reference| **reference != notes_reference && self.push_remote().lease(reference).is_none())
+            .collect();
+        if references.is_empty() {
+            return Ok(());
+        }
+
+        let remote_ls = self.push_remote_mut().remote_ls()?;
+        let recorded_merges = self.recorded_merges()?;
+        for reference in references {
+            let actual = match remote_ls.iter().find(|head| head.name == **reference) {
+                Some(head) => head.oid,
+                None => continue,
+            };
+            let expected = if recorded_merges.contains(&(reference.to_string(), actual)) {
+                actual
+            } else {
+                warn!("{} ({}) on the remote is not recorded in the notes", reference, actual);
+                git2::Oid::zero()
+            };
+            self.push_remote_mut().set_lease(reference, expected);
         }
+        Ok(())
+    }
 
-        Ok((merge, should_merge))
+    /// Returns the merge references and merge commits recorded in every note
+    fn recorded_merges(&self) -> Result<HashSet<(String, git2::Oid)>, git2::Error> {
+        let mut recorded_merges = HashSet::new();
+        let notes = match self.repository.repository.notes(Some(&self.notes_reference())) {
+            Ok(notes) => notes,
+            Err(ref e) if e.code() == git2::ErrorCode::NotFound => return Ok(recorded_merges),
+            Err(e) => return Err(e),
+        };
+        for note in notes {
+            let (_, annotated_oid) = note?;
+            let note = match self.find_note(annotated_oid) {
+                Ok(note) => note,
+                Err(e) => {
+                    warn!("Invalid note for {}: {}", annotated_oid, e);
+                    continue;
+                }
+            };
+            for merge in note.merges.values() {
+                if let Ok(merge_oid) = git2::Oid::from_str(&merge.merge_oid) {
+                    recorded_merges.insert((merge.merge_reference.to_string(), merge_oid));
+                }
+            }
+        }
+        Ok(recorded_merges)
     }
 
     fn merge_commit_message(
@@ -495,6 +683,46 @@ impl<'cb> MergeReferenceNamer<'cb> {
     }
 }
 
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MergeError::Conflict => write!(f, "The merge has conflicts"),
+            MergeError::Push(ref e) => write!(f, "{}", e),
+            MergeError::Git(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for MergeError {
+    fn description(&self) -> &str {
+        match *self {
+            MergeError::Conflict => "The merge has conflicts",
+            MergeError::Push(_) => "Failed to push",
+            MergeError::Git(ref e) => e.message(),
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match *self {
+            MergeError::Conflict => None,
+            MergeError::Push(ref e) => Some(e),
+            MergeError::Git(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<git2::Error> for MergeError {
+    fn from(e: git2::Error) -> MergeError {
+        MergeError::Git(e)
+    }
+}
+
+impl From<PushError> for MergeError {
+    fn from(e: PushError) -> MergeError {
+        MergeError::Push(e)
+    }
+}
+
 impl fmt::Display for ShouldMergeResult {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::ShouldMergeResult::*;
@@ -524,6 +752,11 @@ fn index_in_conflict(entries: &mut git2::IndexEntries) -> bool {
     entries.any(|ref entry| git_index_entry_is_conflict(entry))
 }
 
+// libgit2 reports objects missing from the object database as generic errors
+fn is_missing_object(error: &git2::Error) -> bool {
+    error.code() == git2::ErrorCode::NotFound || error.class() == git2::ErrorClass::Odb
+}
+
 #[cfg(test)]
 mod tests {
     use std::cell::Cell;
@@ -535,8 +768,10 @@ mod tests {
     use git2;
     use rand;
     use rand::Rng;
+    use tempdir::TempDir;
 
-    use merger::{Merge, MergeReferenceNamer, Merger, Note, ShouldMergeResult};
+    use git::PushError;
+    use merger::{Merge, MergeError, MergeReferenceNamer, Merger, Note, ShouldMergeResult};
 
     fn head_oid(repo: &git::Repository) -> git2::Oid {
         let reference = not_err!(repo.repository.head());
@@ -676,6 +911,68 @@ mod tests {
         assert_eq!(2, matching_merges.len());
     }
 
+    #[test]
+    fn conflicting_merges_are_reported() {
+        let (td, _raw) = ::test::raw_repo_init();
+        let config = ::test::config_init(&td);
+        let repo = ::test::repo_init(&config);
+        let merger = not_err!(Merger::new(&repo, None, None, None));
+
+        let head = not_err!(repo.repository.find_commit(head_oid(&repo)));
+        let commit = |content: &str| {
+            let blob = not_err!(repo.repository.blob(content.as_bytes()));
+            let mut builder = not_err!(repo.repository.treebuilder(Some(&not_err!(head.tree()))));
+            not_err!(builder.insert("conflict", blob, 0o100644));
+            let tree = not_err!(repo.repository.find_tree(not_err!(builder.write())));
+            let signature = not_err!(repo.repository.signature());
+            let repository = &repo.repository;
+            not_err!(repository.commit(None, &signature, &signature, content, &tree, &[&head]))
+        };
+        let oid = commit("ours");
+        let branch_oid = commit("theirs");
+
+        let merge = merger.merge(branch_oid, oid, "refs/heads/branch", "refs/heads/master");
+        assert_matches!(merge, Err(MergeError::Conflict));
+    }
+
+    #[test]
+    fn shallow_repositories_are_deepened_to_find_merge_bases() {
+        let (td, _raw) = ::test::raw_repo_init();
+        let config = ::test::config_init(&td);
+        let upstream = ::test::repo_init(&config);
+        add_branch_commit(&upstream);
+        for _ in 0..3 {
+            add_branch_commit_with_reference(&upstream, "refs/heads/master");
+        }
+
+        let checkout = not_err!(TempDir::new("shallow"));
+        let shallow_config = ::RepositoryConfiguration {
+            checkout_path: not_none!(checkout.path().join("repo").to_str()).to_string(),
+            depth: Some(1),
+            deepen_by: Some(1),
+            ..config.clone()
+        };
+        let repo = not_err!(git::Repository::clone(&shallow_config));
+        assert!(repo.repository.is_shallow());
+
+        let merger = not_err!(Merger::new(&repo, None, None, None));
+        let oid = not_err!(repo.repository.refname_to_id("refs/remotes/origin/master"));
+        let branch_oid = not_err!(repo.repository.refname_to_id("refs/remotes/origin/branch"));
+        not_err!(merger.merge(branch_oid, oid, "refs/heads/branch", "refs/heads/master"));
+
+        // Without deepening, the merge base cannot be found
+        let checkout = not_err!(TempDir::new("shallow"));
+        let shallow_config = ::RepositoryConfiguration {
+            checkout_path: not_none!(checkout.path().join("repo").to_str()).to_string(),
+            deepen_by: Some(0),
+            ..shallow_config.clone()
+        };
+        let repo = not_err!(git::Repository::clone(&shallow_config));
+        let merger = not_err!(Merger::new(&repo, None, None, None));
+        let error = is_err!(merger.merge(branch_oid, oid, "refs/heads/branch", "refs/heads/master"));
+        assert!(error.to_string().contains("shallow"), "{}", error);
+    }
+
     #[test]
     fn merge_smoke_test() {
         let (td, _raw) = ::test::raw_repo_init();
@@ -718,8 +1015,9 @@ mod tests {
         let reference = "refs/heads/branch";
         let target_reference = "refs/heads/master";
 
-        let (merge, _should_merge) =
+        let (merge, _should_merge, report) =
             not_err!(merger.check_and_merge(branch_oid, oid, reference, target_reference, false));
+        is_none!(report);
         assert_eq!(merge.target_parent_oid, format!("{}", oid));
         assert_eq!(merge.target_parent_reference, target_reference);
         assert_eq!(merge.parents_oid, vec![format!("{}", branch_oid)]);
@@ -884,4 +1182,122 @@ mod tests {
         }
         assert!(hit.get());
     }
+
+    #[test]
+    fn check_and_merge_targets_stores_merges_in_the_same_note() {
+        let (td, _raw) = ::test::raw_repo_init();
+        let config = ::test::config_init(&td);
+        let repo = ::test::repo_init(&config);
+        let mut merger = not_err!(Merger::new(&repo, None, Some("foobar"), None));
+
+        let oid = head_oid(&repo);
+        let develop_oid = add_branch_commit_with_reference(&repo, "refs/heads/develop");
+        let branch_oid = add_branch_commit(&repo);
+        let reference = "refs/heads/branch";
+        let targets = [("refs/heads/master", oid), ("refs/heads/develop", develop_oid)];
+
+        let (results, report) = not_err!(merger.check_and_merge_targets(branch_oid, reference, &targets, false));
+        is_none!(report);
+        assert_eq!(2, results.len());
+        for (target_reference, result) in results {
+            let (merge, should_merge) = not_err!(result);
+            assert_eq!(target_reference, merge.target_parent_reference);
+            assert_matches!(should_merge, ShouldMergeResult::Merge(_));
+        }
+
+        let note = not_err!(merger.find_note(branch_oid));
+        assert_eq!(2, note.merges.len());
+        assert_eq!(format!("{}", oid), note.merges["refs/heads/master"].target_parent_oid);
+        assert_eq!(
+            format!("{}", develop_oid),
+            note.merges["refs/heads/develop"].target_parent_oid
+        );
+    }
+
+    #[test]
+    fn merges_are_created_in_bare_repositories() {
+        let (td, _raw) = ::test::raw_repo_init();
+        let config = ::test::config_init(&td);
+        let source = ::test::repo_init(&config);
+        let oid = head_oid(&source);
+        let branch_oid = add_branch_commit(&source);
+
+        let td_bare = TempDir::new("bare").unwrap();
+        let mut bare_config = config.clone();
+        bare_config.checkout_path = not_none!(td_bare.path().to_str()).to_string();
+        bare_config.bare = true;
+        let repo = not_err!(git::Repository::clone_or_open(&bare_config));
+        let mut merger = not_err!(Merger::new(&repo, None, None, None));
+
+        let (merge, _should_merge, _report) =
+            not_err!(merger.check_and_merge(branch_oid, oid, "refs/heads/branch", "refs/heads/master", false));
+        let merge_oid = not_err!(git2::Oid::from_str(&merge.merge_oid));
+        not_err!(repo.repository.find_commit(merge_oid));
+    }
+
+    #[test]
+    fn merge_references_unknown_to_the_notes_are_not_overwritten_after_a_restart() {
+        let (td, _raw) = ::test::raw_repo_init();
+        let mut config = ::test::config_init(&td);
+
+        // Local pushes are only supported to bare repositories
+        let td_bare = TempDir::new("bare").unwrap();
+        let bare = not_err!(git2::build::RepoBuilder::new()
+            .bare(true)
+            .clone(&config.uri, td_bare.path()));
+        config.uri = ::test::path2url(td_bare.path());
+
+        let td_new = TempDir::new("test").unwrap();
+        config.checkout_path = not_none!(td_new.path().to_str()).to_string();
+        let repo = not_err!(git::Repository::clone_or_open(&config));
+        {
+            let mut git_config = not_err!(repo.repository.config());
+            not_err!(git_config.set_str("user.name", "name"));
+            not_err!(git_config.set_str("user.email", "email"));
+        }
+
+        let oid = head_oid(&repo);
+        let branch_oid = add_branch_commit(&repo);
+        let merge = {
+            let mut merger = not_err!(Merger::new(&repo, None, None, None));
+            merger.set_push_with_lease(true);
+            let (merge, _should_merge, report) =
+                not_err!(merger.check_and_merge(branch_oid, oid, "refs/heads/branch", "refs/heads/master", true));
+            assert!(not_none!(report).is_success());
+            merge
+        };
+        let references = vec![merge.merge_reference.to_string()];
+
+        // A fresh `Merger` pushes merge references recorded in the notes
+        {
+            let mut merger = not_err!(Merger::new(&repo, None, None, None));
+            merger.set_push_with_lease(true);
+            assert!(not_err!(merger.push(&references)).is_success());
+        }
+
+        // But not merge references changed by someone else
+        not_err!(bare.reference(&merge.merge_reference, oid, true, "test"));
+        let mut merger = not_err!(Merger::new(&repo, None, None, None));
+        merger.set_push_with_lease(true);
+        match merger.push(&references) {
+            Err(PushError::LeaseMismatch(mismatches)) => {
+                assert_eq!(1, mismatches.len());
+                assert_eq!(merge.merge_reference, mismatches[0].reference);
+                assert_eq!(Some(oid), mismatches[0].actual);
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(oid, not_err!(bare.refname_to_id(&merge.merge_reference)));
+
+        // The mismatch is reported when merging and pushing at once too
+        let branch_oid = add_branch_commit(&repo);
+        match merger.check_and_merge(branch_oid, oid, "refs/heads/branch", "refs/heads/master", true) {
+            Err(MergeError::Push(PushError::LeaseMismatch(mismatches))) => {
+                assert_eq!(1, mismatches.len());
+                assert_eq!(merge.merge_reference, mismatches[0].reference);
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(oid, not_err!(bare.refname_to_id(&merge.merge_reference)));
+    }
 }
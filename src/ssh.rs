@@ -0,0 +1,558 @@
+//! Verification of the host keys presented by SSH remotes.
+//!
+//! Host keys are accepted if they match one of the `host_key_fingerprints` pinned for the host, an entry for the
+//! host in the configured `known_hosts` file, or a key recorded for the host in the `trust_on_first_use` file.
+//! Hosts that are seen for the first time have their key recorded if `trust_on_first_use` is configured.
+//!
+//! libgit2 only provides the MD5 and SHA-1 hashes of the host key, so `known_hosts` entries and recorded keys
+//! are compared by their SHA-1 hashes.
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::str;
+use std::str::FromStr;
+use std::vec::Vec;
+
+use hmac::{Hmac, Mac};
+use rustc_serialize::base64::FromBase64;
+use sha1::{Digest, Sha1};
+
+use super::RepositoryConfiguration;
+
+/// A host key fingerprint
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Fingerprint {
+    /// MD5 hash of the host key
+    Md5(Vec<u8>),
+    /// SHA-1 hash of the host key
+    Sha1(Vec<u8>),
+}
+
+impl FromStr for Fingerprint {
+    type Err = String;
+
+    /// Parses fingerprints in the form `md5:<hex>` or `sha1:<hex>`. The hex digits may be separated by colons,
+    /// and the SHA-1 hash may also be base64 encoded, as printed by `ssh-keygen -l -E sha1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let separator = s
+            .find(':')
+            .ok_or_else(|| format!("Fingerprint {} has no algorithm", s))?;
+        let (algorithm, digest) = (s[..separator].to_lowercase(), &s[separator + 1..]);
+        let length = match algorithm.as_str() {
+            "md5" => 16,
+            "sha1" => 20,
+            _ => Err(format!("Fingerprint {} has an unsupported algorithm {}", s, algorithm))?,
+        };
+
+        let hex = digest.replace(':', "");
+        let bytes = if hex.len() == length * 2 {
+            decode_hex(&hex)
+        } else {
+            digest.trim_end_matches('=').from_base64().ok()
+        };
+        let bytes = match bytes {
+            Some(ref bytes) if bytes.len() == length => bytes.clone(),
+            _ => Err(format!("Fingerprint {} is not a valid {} hash", s, algorithm))?,
+        };
+
+        Ok(match algorithm.as_str() {
+            "md5" => Fingerprint::Md5(bytes),
+            _ => Fingerprint::Sha1(bytes),
+        })
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fingerprint::Md5(ref bytes) => write!(f, "md5:{}", encode_hex(bytes)),
+            Fingerprint::Sha1(ref bytes) => write!(f, "sha1:{}", encode_hex(bytes)),
+        }
+    }
+}
+
+/// Errors from verifying host keys
+#[derive(Debug)]
+pub enum HostKeyError {
+    /// The host offered a key that does not match the known or pinned keys
+    Mismatch {
+        /// The host connected to
+        host: String,
+        /// Fingerprint of the key offered by the host
+        fingerprint: Fingerprint,
+    },
+    /// The host is not known and trust on first use is not configured
+    Unknown {
+        /// The host connected to
+        host: String,
+        /// Fingerprint of the key offered by the host
+        fingerprint: Fingerprint,
+    },
+    /// The host did not offer a key that can be verified
+    NoHostKey(String),
+    /// The configuration or one of the files could not be parsed
+    Invalid(String),
+    /// One of the files could not be read or written
+    Io(io::Error),
+}
+
+impl fmt::Display for HostKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HostKeyError::Mismatch {
+                ref host,
+                ref fingerprint,
+            } => write!(
+                f,
+                "Host key verification failed: the key offered by {} ({}) does not match the known host keys. \
+                 The host key might have been changed, or someone might be intercepting the connection",
+                host, fingerprint
+            ),
+            HostKeyError::Unknown {
+                ref host,
+                ref fingerprint,
+            } => write!(
+                f,
+                "Host key verification failed: {} is not a known host. It offered the key {}",
+                host, fingerprint
+            ),
+            HostKeyError::NoHostKey(ref host) => {
+                write!(
+                    f,
+                    "Host key verification failed: {} did not offer an SSH host key",
+                    host
+                )
+            }
+            HostKeyError::Invalid(ref message) => write!(f, "{}", message),
+            HostKeyError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for HostKeyError {
+    fn description(&self) -> &str {
+        match *self {
+            HostKeyError::Mismatch { .. } => "host key mismatch",
+            HostKeyError::Unknown { .. } => "unknown host",
+            HostKeyError::NoHostKey(_) => "no host key",
+            HostKeyError::Invalid(ref message) => message,
+            HostKeyError::Io(_) => "unable to read or write host keys",
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match *self {
+            HostKeyError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HostKeyError {
+    fn from(e: io::Error) -> Self {
+        HostKeyError::Io(e)
+    }
+}
+
+/// A host pattern in a `known_hosts` file
+#[derive(Clone, Debug)]
+enum HostPattern {
+    Plain(String),
+    Hashed { salt: Vec<u8>, hash: Vec<u8> },
+}
+
+impl HostPattern {
+    fn matches(&self, host: &str) -> bool {
+        match *self {
+            // Patterns with a port, i.e. `[host]:2222`, are matched by their host. libgit2 does not tell us the port.
+            HostPattern::Plain(ref pattern) => {
+                let pattern = match pattern.strip_prefix('[') {
+                    Some(pattern) => pattern.split("]:").next().unwrap_or(""),
+                    None => pattern,
+                };
+                pattern.eq_ignore_ascii_case(host)
+            }
+            HostPattern::Hashed { ref salt, ref hash } => match Hmac::<Sha1>::new_varkey(salt) {
+                Ok(mut mac) => {
+                    mac.input(host.to_lowercase().as_bytes());
+                    mac.verify(hash).is_ok()
+                }
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+/// An entry in a `known_hosts` file
+#[derive(Clone, Debug)]
+struct KnownHost {
+    patterns: Vec<HostPattern>,
+    fingerprint: Fingerprint,
+}
+
+impl KnownHost {
+    /// Parses a line of a `known_hosts` file. Returns `None` for lines that are comments, or are not supported
+    fn parse(line: &str) -> Result<Option<KnownHost>, String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('@') {
+            return Ok(None);
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 {
+            Err(format!("Invalid known_hosts entry: {}", line))?;
+        }
+        let key = fields[2]
+            .from_base64()
+            .map_err(|e| format!("Invalid key in known_hosts entry {}: {}", line, e))?;
+
+        let mut patterns = vec![];
+        for pattern in fields[0].split(',') {
+            if let Some(hashed) = pattern.strip_prefix("|1|") {
+                let mut parts = hashed.split('|');
+                let salt = parts.next().and_then(|salt| salt.from_base64().ok());
+                let hash = parts.next().and_then(|hash| hash.from_base64().ok());
+                match (salt, hash) {
+                    (Some(salt), Some(hash)) => patterns.push(HostPattern::Hashed { salt, hash }),
+                    _ => Err(format!("Invalid hashed host in known_hosts entry: {}", line))?,
+                }
+            } else if pattern.contains(&['*', '?', '!'][..]) {
+                debug!("Ignoring unsupported known_hosts pattern {}", pattern);
+            } else {
+                patterns.push(HostPattern::Plain(pattern.to_string()));
+            }
+        }
+
+        Ok(Some(KnownHost {
+            patterns,
+            fingerprint: Fingerprint::Sha1(sha1(&key)),
+        }))
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.matches(host))
+    }
+}
+
+/// Verifies host keys against the `known_hosts`, `host_key_fingerprints` and `trust_on_first_use`
+/// configuration of a repository
+#[derive(Clone, Debug)]
+pub struct HostKeyVerifier {
+    known_hosts: Vec<KnownHost>,
+    fingerprints: HashMap<String, Vec<Fingerprint>>,
+    trust_on_first_use: Option<PathBuf>,
+}
+
+impl HostKeyVerifier {
+    /// Returns whether host key verification is configured for the repository
+    pub fn is_configured(repo_details: &RepositoryConfiguration) -> bool {
+        repo_details.known_hosts.is_some()
+            || !repo_details.host_key_fingerprints.is_empty()
+            || repo_details.trust_on_first_use.is_some()
+    }
+
+    /// Create a verifier from the configuration of a repository. The `known_hosts` file is read immediately.
+    pub fn new(repo_details: &RepositoryConfiguration) -> Result<HostKeyVerifier, HostKeyError> {
+        let known_hosts = match repo_details.known_hosts {
+            Some(ref path) => {
+                let mut contents = String::new();
+                File::open(path)?.read_to_string(&mut contents)?;
+                let mut known_hosts = vec![];
+                for line in contents.lines() {
+                    known_hosts.extend(KnownHost::parse(line).map_err(HostKeyError::Invalid)?);
+                }
+                known_hosts
+            }
+            None => vec![],
+        };
+
+        let mut fingerprints = HashMap::new();
+        for (host, host_fingerprints) in &repo_details.host_key_fingerprints {
+            let host_fingerprints = host_fingerprints
+                .iter()
+                .map(|fingerprint| Fingerprint::from_str(fingerprint))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(HostKeyError::Invalid)?;
+            fingerprints.insert(host.to_lowercase(), host_fingerprints);
+        }
+
+        Ok(HostKeyVerifier {
+            known_hosts,
+            fingerprints,
+            trust_on_first_use: repo_details.trust_on_first_use.as_ref().map(PathBuf::from),
+        })
+    }
+
+    /// Verify the key offered by `host`, given its MD5 and SHA-1 hashes. If the host is seen for the first time
+    /// and `trust_on_first_use` is configured, the key is recorded and accepted.
+    pub fn verify(&self, host: &str, md5: Option<&[u8]>, sha1: Option<&[u8]>) -> Result<(), HostKeyError> {
+        let offered: Vec<Fingerprint> = sha1
+            .map(|hash| Fingerprint::Sha1(hash.to_vec()))
+            .into_iter()
+            .chain(md5.map(|hash| Fingerprint::Md5(hash.to_vec())))
+            .collect();
+        if offered.is_empty() {
+            Err(HostKeyError::NoHostKey(host.to_string()))?;
+        }
+
+        let pinned = self
+            .fingerprints
+            .get(&host.to_lowercase())
+            .map_or(&[][..], |fingerprints| &fingerprints[..]);
+        if pinned.iter().any(|fingerprint| offered.contains(fingerprint)) {
+            return Ok(());
+        }
+
+        let known_hosts: Vec<&KnownHost> = self.known_hosts.iter().filter(|known| known.matches(host)).collect();
+        if known_hosts.iter().any(|known| offered.contains(&known.fingerprint)) {
+            return Ok(());
+        }
+
+        let recorded = self.recorded(host)?;
+        if recorded.iter().any(|fingerprint| offered.contains(fingerprint)) {
+            return Ok(());
+        }
+
+        let fingerprint = offered[0].clone();
+        if !known_hosts.is_empty() || !recorded.is_empty() || !pinned.is_empty() {
+            Err(HostKeyError::Mismatch {
+                host: host.to_string(),
+                fingerprint,
+            })
+        } else if let Some(ref path) = self.trust_on_first_use {
+            warn!("Trusting host key {} of {} on first use", fingerprint, host);
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{} {}", host, fingerprint)?;
+            Ok(())
+        } else {
+            Err(HostKeyError::Unknown {
+                host: host.to_string(),
+                fingerprint,
+            })
+        }
+    }
+
+    /// Returns the fingerprints recorded for `host` in the `trust_on_first_use` file
+    fn recorded(&self, host: &str) -> Result<Vec<Fingerprint>, HostKeyError> {
+        let path = match self.trust_on_first_use {
+            Some(ref path) if path.exists() => path,
+            _ => return Ok(vec![]),
+        };
+
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        let mut fingerprints = vec![];
+        for line in contents.lines() {
+            let mut fields = line.split_whitespace();
+            if let (Some(recorded_host), Some(fingerprint)) = (fields.next(), fields.next()) {
+                if recorded_host.eq_ignore_ascii_case(host) {
+                    fingerprints.push(Fingerprint::from_str(fingerprint).map_err(HostKeyError::Invalid)?);
+                }
+            }
+        }
+        Ok(fingerprints)
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match str::from_utf8(pair) {
+            Ok(pair) if pair.len() == 2 => u8::from_str_radix(pair, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// SHA-1 hash of a host key, to compare with the hashes provided by libgit2
+fn sha1(data: &[u8]) -> Vec<u8> {
+    Sha1::digest(data).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::str::FromStr;
+
+    use hmac::{Hmac, Mac};
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+    use sha1::Sha1;
+    use tempdir::TempDir;
+
+    use super::{decode_hex, sha1, Fingerprint, HostKeyError, HostKeyVerifier};
+    use RepositoryConfiguration;
+
+    const KEY: &[u8] = b"not really an ssh-ed25519 key";
+
+    fn fingerprint(data: &[u8]) -> Vec<u8> {
+        sha1(data).to_vec()
+    }
+
+    #[test]
+    fn fingerprints_are_parsed() {
+        let md5 = vec![
+            0x16, 0x27, 0xac, 0xa5, 0x76, 0x28, 0x2d, 0x36, 0x63, 0x1b, 0x56, 0x4d, 0xeb, 0xdf, 0xa6, 0x48,
+        ];
+        assert_eq!(
+            Fingerprint::Md5(md5.clone()),
+            not_err!(Fingerprint::from_str("md5:1627aca576282d36631b564debdfa648"))
+        );
+        assert_eq!(
+            Fingerprint::Md5(md5),
+            not_err!(Fingerprint::from_str(
+                "MD5:16:27:ac:a5:76:28:2d:36:63:1b:56:4d:eb:df:a6:48"
+            ))
+        );
+
+        let sha1 = fingerprint(KEY);
+        let base64 = sha1.to_base64(STANDARD);
+        assert_eq!(
+            Fingerprint::Sha1(sha1.clone()),
+            not_err!(Fingerprint::from_str(&format!("SHA1:{}", base64.trim_end_matches('='))))
+        );
+        assert_eq!(
+            Fingerprint::Sha1(sha1.clone()),
+            not_err!(Fingerprint::from_str(&Fingerprint::Sha1(sha1).to_string()))
+        );
+
+        is_err!(Fingerprint::from_str("1627aca576282d36631b564debdfa648"));
+        is_err!(Fingerprint::from_str("sha256:1627aca576282d36631b564debdfa648"));
+        is_err!(Fingerprint::from_str("sha1:1627aca576282d36631b564debdfa648"));
+    }
+
+    #[test]
+    fn host_keys_are_verified_against_known_hosts() {
+        let td = TempDir::new("fusionner").unwrap();
+        let known_hosts = td.path().join("known_hosts");
+        let key = KEY.to_base64(STANDARD);
+        let salt = b"some salt";
+        let mut mac = not_err!(Hmac::<Sha1>::new_varkey(salt));
+        mac.input(b"hashed.example.com");
+        let hashed = format!(
+            "|1|{}|{}",
+            salt.to_base64(STANDARD),
+            mac.result().code().to_base64(STANDARD)
+        );
+        let mut file = not_err!(File::create(&known_hosts));
+        not_err!(writeln!(file, "# A comment"));
+        not_err!(writeln!(
+            file,
+            "github.com,[mirror.example.com]:2222 ssh-ed25519 {} comment",
+            key
+        ));
+        not_err!(writeln!(file, "{} ssh-ed25519 {}", hashed, key));
+        not_err!(writeln!(file, "*.example.com ssh-ed25519 {}", key));
+
+        let config = RepositoryConfiguration {
+            known_hosts: Some(known_hosts.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        assert!(HostKeyVerifier::is_configured(&config));
+        let verifier = not_err!(HostKeyVerifier::new(&config));
+
+        let sha1 = fingerprint(KEY);
+        not_err!(verifier.verify("github.com", None, Some(&sha1)));
+        not_err!(verifier.verify("mirror.example.com", None, Some(&sha1)));
+        not_err!(verifier.verify("hashed.example.com", None, Some(&sha1)));
+
+        let other = fingerprint(b"another key");
+        assert_matches!(
+            verifier.verify("github.com", None, Some(&other)),
+            Err(HostKeyError::Mismatch { .. })
+        );
+        assert_matches!(
+            verifier.verify("unknown.example.com", None, Some(&sha1)),
+            Err(HostKeyError::Unknown { .. })
+        );
+        assert_matches!(
+            verifier.verify("github.com", None, None),
+            Err(HostKeyError::NoHostKey(_))
+        );
+    }
+
+    #[test]
+    fn host_keys_are_verified_against_pinned_fingerprints() {
+        let md5 = not_none!(decode_hex("1627aca576282d36631b564debdfa648"));
+        let sha1 = fingerprint(KEY);
+        let other = fingerprint(b"another key");
+        let config = RepositoryConfiguration {
+            host_key_fingerprints: vec![
+                (
+                    "GitHub.com".to_string(),
+                    vec![
+                        "md5:1627aca576282d36631b564debdfa648".to_string(),
+                        Fingerprint::Sha1(sha1.clone()).to_string(),
+                    ],
+                ),
+                (
+                    "mirror.example.com".to_string(),
+                    vec![Fingerprint::Sha1(other.clone()).to_string()],
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let verifier = not_err!(HostKeyVerifier::new(&config));
+
+        not_err!(verifier.verify("github.com", Some(&md5), None));
+        not_err!(verifier.verify("github.com", None, Some(&sha1)));
+        not_err!(verifier.verify("mirror.example.com", None, Some(&other)));
+
+        // Keys pinned for one host are not accepted for another
+        assert_matches!(
+            verifier.verify("github.com", Some(&other[..16]), Some(&other)),
+            Err(HostKeyError::Mismatch { .. })
+        );
+        assert_matches!(
+            verifier.verify("mirror.example.com", None, Some(&sha1)),
+            Err(HostKeyError::Mismatch { .. })
+        );
+        assert_matches!(
+            verifier.verify("gitlab.com", None, Some(&sha1)),
+            Err(HostKeyError::Unknown { .. })
+        );
+
+        let config = RepositoryConfiguration {
+            host_key_fingerprints: vec![("github.com".to_string(), vec!["sha1:not a fingerprint".to_string()])]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        assert_matches!(HostKeyVerifier::new(&config), Err(HostKeyError::Invalid(_)));
+    }
+
+    #[test]
+    fn host_keys_are_trusted_on_first_use() {
+        let td = TempDir::new("fusionner").unwrap();
+        let trusted = td.path().join("trusted_hosts");
+        let config = RepositoryConfiguration {
+            trust_on_first_use: Some(trusted.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        let verifier = not_err!(HostKeyVerifier::new(&config));
+
+        let sha1 = fingerprint(KEY);
+        not_err!(verifier.verify("github.com", None, Some(&sha1)));
+        not_err!(verifier.verify("github.com", None, Some(&sha1)));
+
+        let mut contents = String::new();
+        not_err!(not_err!(File::open(&trusted)).read_to_string(&mut contents));
+        assert_eq!(format!("github.com {}\n", Fingerprint::Sha1(sha1.clone())), contents);
+
+        let other = fingerprint(b"another key");
+        assert_matches!(
+            verifier.verify("github.com", None, Some(&other)),
+            Err(HostKeyError::Mismatch { .. })
+        );
+        not_err!(verifier.verify("gitlab.com", None, Some(&other)));
+    }
+}
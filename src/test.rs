@@ -93,12 +93,16 @@ pub fn config_init(tempdir: &TempDir) -> ::RepositoryConfiguration {
         checkout_path: path.to_str().unwrap().to_string(),
         fetch_refspecs: vec![],
         push_refspecs: vec![],
-        username: Some("foobar".to_string()),
-        password: Some(::Password::new("password")),
-        key: Some("/path/to/some.key".to_string()),
-        key_passphrase: None,
+        credentials: ::Credentials {
+            username: Some("foobar".to_string()),
+            password: Some(::Password::new("password")),
+            key: Some("/path/to/some.key".to_string()),
+            key_passphrase: None,
+            ..Default::default()
+        },
         signature_name: None,
         signature_email: None,
+        ..Default::default()
     }
 }
 